@@ -0,0 +1,497 @@
+use crate::bsp::map_coords;
+use crate::prop::LoadedProp;
+use three_d::*;
+use tracing::warn;
+use vmdl::mdl::{Animation, AnimationFlags, Bone};
+
+/// A looping sequence of a prop, evaluated into bone matrices for skinning
+#[derive(Clone)]
+pub struct PropAnimation {
+    /// Name of the model and the played sequence
+    pub name: String,
+    fps: f32,
+    /// Position and rotation of every bone relative to its parent, for every frame
+    frames: Vec<Vec<(Vec3, Quat)>>,
+    parents: Vec<Option<usize>>,
+    /// Transform from the bind pose into the space of every bone
+    pose_to_bone: Vec<Mat4>,
+    /// Rotation into the idle pose, applied to the vertices of the model like for unanimated props
+    root_transform: Mat4,
+}
+
+/// A vertex in model space with the bones that move it
+#[derive(Debug, Clone, Copy)]
+pub struct SkinnedVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub tangent: Vec4,
+    pub uv: Vec2,
+    pub weights: [(u8, f32); 3],
+}
+
+impl PropAnimation {
+    /// Load a sequence by its label, `None` if the model doesn't have it
+    pub fn new(prop: &LoadedProp, model: &str, sequence: &str) -> Option<Self> {
+        let mdl = &prop.mdl;
+        let Some(index) = mdl
+            .animation_sequences
+            .iter()
+            .position(|seq| seq.label.eq_ignore_ascii_case(sequence))
+        else {
+            warn!(model, sequence, "unknown animation sequence");
+            return None;
+        };
+        let Some(animation) = prop
+            .sequence_animation(index)
+            .and_then(|animation| mdl.local_animations.get(animation))
+        else {
+            warn!(model, sequence, "no animation found for sequence");
+            return None;
+        };
+
+        let frames = (0..animation.frame_count.max(1))
+            .map(|frame| {
+                mdl.bones
+                    .iter()
+                    .enumerate()
+                    .map(|(i, bone)| {
+                        let channel = animation.animations.iter().find(|a| a.bone as usize == i);
+                        bone_pose(bone, channel, frame)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Some(PropAnimation {
+            name: format!("{model}: {}", mdl.animation_sequences[index].label),
+            fps: animation.fps,
+            frames,
+            parents: mdl
+                .bones
+                .iter()
+                .map(|bone| usize::try_from(bone.parent).ok())
+                .collect(),
+            pose_to_bone: mdl
+                .bones
+                .iter()
+                .map(|bone| bone.pose_to_bone.into())
+                .collect(),
+            root_transform: prop.root_transform(),
+        })
+    }
+
+    /// The skinning matrix of every bone in viewer coordinates at a time in seconds, looping the sequence
+    pub fn bone_matrices(&self, time: f32) -> Vec<Mat4> {
+        let count = self.frames.len();
+        let frame = (time * self.fps).rem_euclid(count as f32);
+        let (current, t) = (frame.floor() as usize % count, frame.fract());
+        let next = (current + 1) % count;
+
+        let mut world: Vec<Mat4> = Vec::with_capacity(self.parents.len());
+        for (i, ((a, b), parent)) in self.frames[current]
+            .iter()
+            .zip(&self.frames[next])
+            .zip(&self.parents)
+            .enumerate()
+        {
+            let position = a.0 + (b.0 - a.0) * t;
+            let local = Mat4::from_translation(position) * Mat4::from(a.1.nlerp(b.1, t));
+            // parents always come before their children
+            let parent = parent
+                .filter(|parent| *parent < i)
+                .map(|parent| world[parent]);
+            world.push(parent.map_or(local, |parent| parent * local));
+        }
+        let to_viewer = viewer_coords();
+        let from_viewer = to_viewer.invert().unwrap_or_else(Mat4::identity);
+        world
+            .iter()
+            .zip(&self.pose_to_bone)
+            .map(|(world, pose_to_bone)| {
+                to_viewer * self.root_transform * world * pose_to_bone * from_viewer
+            })
+            .collect()
+    }
+}
+
+/// The bone's position and rotation relative to its parent at a frame
+fn bone_pose(bone: &Bone, animation: Option<&Animation>, frame: usize) -> (Vec3, Quat) {
+    let mut position: Vec3 = bone.pos.into();
+    let mut rotation: Quat = bone.quaternion.into();
+    let Some(animation) = animation else {
+        return (position, rotation);
+    };
+    let delta = animation.flags.contains(AnimationFlags::STUDIO_ANIM_DELTA);
+
+    if animation
+        .flags
+        .intersects(AnimationFlags::STUDIO_ANIM_RAWROT | AnimationFlags::STUDIO_ANIM_RAWROT2)
+    {
+        rotation = animation.rotation(frame).into();
+    } else if animation
+        .flags
+        .contains(AnimationFlags::STUDIO_ANIM_ANIMROT)
+    {
+        // animated rotations are euler angles added to the bind pose angles, unless the sequence is a delta
+        let offset = animated_angles(animation.rotation(frame).into());
+        let angles = if delta {
+            offset
+        } else {
+            Vec3::new(bone.rot.x, bone.rot.y, bone.rot.z) + offset
+        };
+        rotation = angle_quaternion(angles);
+    }
+
+    if animation.flags.contains(AnimationFlags::STUDIO_ANIM_RAWPOS) {
+        position = animation.position(frame).into();
+    } else if animation
+        .flags
+        .contains(AnimationFlags::STUDIO_ANIM_ANIMPOS)
+    {
+        let offset: Vec3 = animation.position(frame).into();
+        position = if delta { offset } else { position + offset };
+    }
+    (position, rotation.normalize())
+}
+
+/// The euler angles of an animated rotation, in the order of the bone's angles
+///
+/// vmdl only gives the animated angles as a quaternion, which it builds from the reordered angles `(x, y, z)`
+/// as `yaw(y) * pitch(-x) * roll(z)`, so the angles are taken back out of the rotation matrix.
+fn animated_angles(rotation: Quat) -> Vec3 {
+    let matrix = Mat3::from(rotation);
+    let x = matrix.z.y.clamp(-1.0, 1.0).asin();
+    let y = matrix.z.x.atan2(matrix.z.z);
+    let z = matrix.x.y.atan2(matrix.y.y);
+    // the decoded angles `[a, b, c]` are stored as `x: c, y: a, z: b`
+    Vec3::new(y, z, x)
+}
+
+/// Rotation from euler angles in radians, like the game's `AngleQuaternion`
+fn angle_quaternion(angles: Vec3) -> Quat {
+    let (sr, cr) = (angles.x * 0.5).sin_cos();
+    let (sp, cp) = (angles.y * 0.5).sin_cos();
+    let (sy, cy) = (angles.z * 0.5).sin_cos();
+    Quat::new(
+        cr * cp * cy + sr * sp * sy,
+        sr * cp * cy - cr * sp * sy,
+        cr * sp * cy + sr * cp * sy,
+        cr * cp * sy - sr * sp * cy,
+    )
+}
+
+#[test]
+fn test_animated_angles() {
+    use vmdl::RadianEuler;
+
+    let stored = RadianEuler {
+        x: 0.3,
+        y: -0.5,
+        z: 1.1,
+    };
+    let angles = animated_angles(three_d::Quaternion::from(stored));
+    assert!((angles - Vec3::new(-0.5, 1.1, 0.3)).magnitude() < 0.0001);
+
+    let yaw = angle_quaternion(Vec3::new(0.0, 0.0, 0.7));
+    assert!((yaw - Quat::from_angle_z(three_d::Rad(0.7))).magnitude() < 0.0001);
+}
+
+/// Matrix of [`map_coords`], from model into viewer coordinates
+fn viewer_coords() -> Mat4 {
+    Mat3::from_cols(
+        map_coords(Vec3::unit_x()),
+        map_coords(Vec3::unit_y()),
+        map_coords(Vec3::unit_z()),
+    )
+    .into()
+}
+
+#[test]
+fn test_bone_matrices() {
+    let animation = PropAnimation {
+        name: String::new(),
+        fps: 30.0,
+        frames: vec![vec![(
+            Vec3::new(0.0, 0.0, 2.0),
+            Quat::from_sv(1.0, Vec3::zero()),
+        )]],
+        parents: vec![None],
+        pose_to_bone: vec![Mat4::identity()],
+        root_transform: Mat4::identity(),
+    };
+    let bones = animation.bone_matrices(0.0);
+    let position = bones[0] * map_coords(Vec3::new(1.0, 0.0, 0.0)).extend(1.0);
+    let expected = map_coords(Vec3::new(1.0, 0.0, 2.0));
+    assert!((position.truncate() - expected).magnitude() < 0.0001);
+}
+
+/// Most bones a model can have
+const MAX_BONES: usize = 128;
+
+/// An instanced mesh of an animated prop, the vertices are moved by the bones in the vertex shader
+pub struct SkinnedMesh {
+    context: Context,
+    positions: VertexBuffer,
+    normals: VertexBuffer,
+    tangents: VertexBuffer,
+    uvs: VertexBuffer,
+    bone_ids: VertexBuffer,
+    bone_weights: VertexBuffer,
+    /// The rows of the instance transforms
+    instance_rows: [InstanceBuffer; 3],
+    instance_colors: Option<InstanceBuffer>,
+    transforms: Vec<Mat4>,
+    bones: Vec<Mat4>,
+    /// Bones that move any of the vertices
+    used_bones: Vec<usize>,
+    /// Bounds of the vertices in the bind pose
+    bind_aabb: AxisAlignedBoundingBox,
+    aabb: AxisAlignedBoundingBox,
+}
+
+impl SkinnedMesh {
+    pub fn new(context: &Context, vertices: &[SkinnedVertex], instances: &Instances) -> Self {
+        let positions: Vec<Vec3> = vertices
+            .iter()
+            .map(|vertex| map_coords(vertex.position))
+            .collect();
+        let normals: Vec<Vec3> = vertices
+            .iter()
+            .map(|vertex| map_coords(vertex.normal))
+            .collect();
+        let tangents: Vec<Vec4> = vertices
+            .iter()
+            .map(|vertex| map_coords(vertex.tangent.truncate()).extend(vertex.tangent.w))
+            .collect();
+        let uvs: Vec<Vec2> = vertices
+            .iter()
+            .map(|vertex| vec2(vertex.uv.x, 1.0 - vertex.uv.y))
+            .collect();
+        let (bone_ids, bone_weights): (Vec<Vec3>, Vec<Vec3>) = vertices
+            .iter()
+            .map(|vertex| {
+                let [a, b, c] = vertex.weights;
+                let ids = [a, b, c].map(|(bone, _)| (bone as usize).min(MAX_BONES - 1) as f32);
+                let weights = Vec3::new(a.1, b.1, c.1);
+                let total = weights.x + weights.y + weights.z;
+                // vertices are always weighted to a bone, fall back to the root bone just in case
+                let weights = if total > 0.0 {
+                    weights / total
+                } else {
+                    Vec3::unit_x()
+                };
+                (Vec3::from(ids), weights)
+            })
+            .unzip();
+        let mut used_bones: Vec<usize> = vertices
+            .iter()
+            .flat_map(|vertex| vertex.weights)
+            .filter(|(_, weight)| *weight > 0.0)
+            .map(|(bone, _)| bone as usize)
+            .collect();
+        used_bones.sort_unstable();
+        used_bones.dedup();
+
+        let mut mesh = SkinnedMesh {
+            context: context.clone(),
+            positions: VertexBuffer::new_with_data(context, &positions),
+            normals: VertexBuffer::new_with_data(context, &normals),
+            tangents: VertexBuffer::new_with_data(context, &tangents),
+            uvs: VertexBuffer::new_with_data(context, &uvs),
+            bone_ids: VertexBuffer::new_with_data(context, &bone_ids),
+            bone_weights: VertexBuffer::new_with_data(context, &bone_weights),
+            instance_rows: [
+                InstanceBuffer::new(context),
+                InstanceBuffer::new(context),
+                InstanceBuffer::new(context),
+            ],
+            instance_colors: None,
+            transforms: Vec::new(),
+            bones: Vec::new(),
+            used_bones,
+            bind_aabb: AxisAlignedBoundingBox::new_with_positions(&positions),
+            aabb: AxisAlignedBoundingBox::EMPTY,
+        };
+        mesh.set_instances(instances);
+        mesh
+    }
+
+    /// Upload the instances, reusing the existing buffers
+    pub fn set_instances(&mut self, instances: &Instances) {
+        self.transforms.clone_from(&instances.transformations);
+        for (row, buffer) in self.instance_rows.iter_mut().enumerate() {
+            let data: Vec<Vec4> = self
+                .transforms
+                .iter()
+                .map(|transform| transform.row(row))
+                .collect();
+            buffer.fill(&data);
+        }
+        match (&instances.colors, &mut self.instance_colors) {
+            (Some(colors), Some(buffer)) => buffer.fill(&linear_colors(colors)),
+            (Some(colors), None) => {
+                self.instance_colors = Some(InstanceBuffer::new_with_data(
+                    &self.context,
+                    &linear_colors(colors),
+                ))
+            }
+            (None, _) => self.instance_colors = None,
+        }
+        self.update_aabb();
+    }
+
+    /// Set the skinning matrix of every bone, as given by [`PropAnimation::bone_matrices`]
+    pub fn set_bones(&mut self, bones: &[Mat4]) {
+        self.bones.clear();
+        self.bones.extend(bones.iter().take(MAX_BONES));
+        self.update_aabb();
+    }
+
+    fn update_aabb(&mut self) {
+        // every vertex is a weighted average of its position moved by each of its bones
+        let mut posed = AxisAlignedBoundingBox::EMPTY;
+        for bone in self
+            .used_bones
+            .iter()
+            .filter_map(|bone| self.bones.get(*bone))
+        {
+            let mut aabb = self.bind_aabb;
+            aabb.transform(bone);
+            posed.expand_with_aabb(&aabb);
+        }
+        if posed.is_empty() {
+            posed = self.bind_aabb;
+        }
+        self.aabb = AxisAlignedBoundingBox::EMPTY;
+        for transform in &self.transforms {
+            let mut aabb = posed;
+            aabb.transform(transform);
+            self.aabb.expand_with_aabb(&aabb);
+        }
+    }
+}
+
+fn linear_colors(colors: &[Srgba]) -> Vec<Vec4> {
+    colors.iter().map(|color| color.to_linear_srgb()).collect()
+}
+
+impl Geometry for SkinnedMesh {
+    fn draw(
+        &self,
+        camera: &Camera,
+        program: &Program,
+        render_states: RenderStates,
+        attributes: FragmentAttributes,
+    ) {
+        if self.transforms.is_empty() || self.bones.is_empty() {
+            return;
+        }
+        program.use_uniform("viewProjection", camera.projection() * camera.view());
+        program.use_uniform_array("bones", &self.bones);
+        program.use_vertex_attribute("position", &self.positions);
+        program.use_vertex_attribute("bone_ids", &self.bone_ids);
+        program.use_vertex_attribute("bone_weights", &self.bone_weights);
+        for (name, buffer) in ["row1", "row2", "row3"]
+            .into_iter()
+            .zip(&self.instance_rows)
+        {
+            program.use_instance_attribute(name, buffer);
+        }
+        if attributes.normal {
+            program.use_vertex_attribute("normal", &self.normals);
+        }
+        if attributes.normal && attributes.tangents {
+            program.use_vertex_attribute("tangent", &self.tangents);
+        }
+        if attributes.uv {
+            program.use_vertex_attribute("uv_coordinates", &self.uvs);
+        }
+        if attributes.color {
+            if let Some(colors) = &self.instance_colors {
+                program.use_instance_attribute("instance_color", colors);
+            }
+        }
+        program.draw_arrays_instanced(
+            render_states,
+            camera.viewport(),
+            self.positions.vertex_count(),
+            self.transforms.len() as u32,
+        );
+    }
+
+    fn vertex_shader_source(&self, required_attributes: FragmentAttributes) -> String {
+        format!(
+            "#define MAX_BONES {MAX_BONES}\n{}{}{}{}{}",
+            if required_attributes.normal {
+                "#define USE_NORMALS\n"
+            } else {
+                ""
+            },
+            if required_attributes.normal && required_attributes.tangents {
+                "#define USE_TANGENTS\n"
+            } else {
+                ""
+            },
+            if required_attributes.uv {
+                "#define USE_UVS\n"
+            } else {
+                ""
+            },
+            if required_attributes.color && self.instance_colors.is_some() {
+                "#define USE_INSTANCE_COLORS\n"
+            } else {
+                ""
+            },
+            include_str!("shaders/skinned.vert"),
+        )
+    }
+
+    fn id(&self, required_attributes: FragmentAttributes) -> u16 {
+        let mut id = 0b1u16 << 9;
+        if required_attributes.normal {
+            id |= 0b1u16;
+        }
+        if required_attributes.normal && required_attributes.tangents {
+            id |= 0b1u16 << 1;
+        }
+        if required_attributes.uv {
+            id |= 0b1u16 << 2;
+        }
+        if required_attributes.color && self.instance_colors.is_some() {
+            id |= 0b1u16 << 3;
+        }
+        id
+    }
+
+    fn render_with_material(
+        &self,
+        material: &dyn Material,
+        camera: &Camera,
+        lights: &[&dyn Light],
+    ) {
+        render_with_material(&self.context, camera, self, material, lights)
+    }
+
+    fn render_with_effect(
+        &self,
+        material: &dyn Effect,
+        camera: &Camera,
+        lights: &[&dyn Light],
+        color_texture: Option<ColorTexture>,
+        depth_texture: Option<DepthTexture>,
+    ) {
+        render_with_effect(
+            &self.context,
+            camera,
+            self,
+            material,
+            lights,
+            color_texture,
+            depth_texture,
+        )
+    }
+
+    fn aabb(&self) -> AxisAlignedBoundingBox {
+        self.aabb
+    }
+}
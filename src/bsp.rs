@@ -1,42 +1,136 @@
-use crate::material::{convert_material, load_material_fallback};
-use crate::prop::load_props;
+use crate::deps::entity_files;
+use crate::fog::{load_fog, Fog};
+use crate::info::MapInfo;
+use crate::light::{load_environment_light, load_lights, EnvironmentLight, MapLight};
+use crate::lightmap::{Lightmap, LightmapAtlas};
+use crate::loader::Loader;
+use crate::material::{convert_material, load_material_fallback, AssetLog};
+use crate::prop::{load_props, load_screen_fade, MapProp, PropFade, Props};
+use crate::sky::{load_sky, Sky};
+use crate::vis::{face_clusters, Visibility};
 use crate::Error;
 use cgmath::Matrix4;
 use itertools::Itertools;
-use std::collections::{HashMap, HashSet};
-use tf_asset_loader::Loader;
-use three_d::{CpuModel, Positions, Vec3};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use three_d::{
+    AxisAlignedBoundingBox, CpuModel, Indices, InnerSpace, Positions, Srgba, Vec2, Vec3,
+};
 use three_d_asset::{Geometry, Primitive, TriMesh};
-use vbsp::{AsPropPlacement, Bsp, Handle, Vector};
+use tracing::warn;
+use vbsp::{AsPropPlacement, Bsp, Face, Handle, Vector};
 use vbsp_entities_tf2::Entity;
 
+pub struct Map {
+    pub models: Vec<CpuModel>,
+    pub props: Props,
+    /// Bounds of the world model
+    pub bounds: AxisAlignedBoundingBox,
+    /// Baked lighting for the world model, the first of the models
+    pub lightmap: Option<Lightmap>,
+    pub visibility: Option<Visibility>,
+    pub sky: Sky,
+    pub environment_light: Option<EnvironmentLight>,
+    pub lights: Vec<MapLight>,
+    pub fog: Option<Fog>,
+    pub info: MapInfo,
+    /// Files loaded for the map's materials, textures and models
+    pub assets: BTreeSet<String>,
+    /// Materials, textures and models that failed to load, with the error
+    pub missing: BTreeMap<String, String>,
+    /// Files in the map's pakfile
+    pub packed: BTreeSet<String>,
+    /// Files referenced by entities that aren't loaded for the viewer
+    pub entity_files: BTreeSet<String>,
+}
+
 pub fn load_map(
     data: &[u8],
     loader: &mut Loader,
     props: bool,
     textures: bool,
-) -> Result<Vec<CpuModel>, Error> {
-    let (world, bsp) = load_world(data, loader, textures)?;
-    let mut models = Vec::with_capacity(bsp.static_props().count() + 1);
-    models.push(world);
+) -> Result<Map, Error> {
+    let assets = AssetLog::default();
+    let (mut map, bsp) = load_world(data, loader, textures, &assets)?;
     // println!("{:#?}", bsp.entities);
-    let entity_props = bsp
-        .entities
-        .iter()
-        .flat_map(|ent| ent.parse::<Entity>())
-        .filter_map(|ent| match ent {
-            Entity::PropDynamic(prop) => Some(prop.as_prop_placement()),
-            Entity::PropPhysics(prop) => Some(prop.as_prop_placement()),
-            Entity::PropDynamicOverride(prop) => Some(prop.as_prop_placement()),
-            _ => None,
-        });
-    let static_props = bsp.static_props().map(|prop| prop.as_prop_placement());
+    let entity_props = bsp.entities.iter().filter_map(|ent| {
+        let placement = match ent.parse::<Entity>().ok()? {
+            Entity::PropDynamic(prop) => prop.as_prop_placement(),
+            Entity::PropPhysics(prop) => prop.as_prop_placement(),
+            Entity::PropDynamicOverride(prop) => prop.as_prop_placement(),
+            _ => return None,
+        };
+        // the typed entities don't agree on the types of the fade and body properties, so we read them raw
+        let float = |key| ent.prop_parse::<f32>(key).and_then(Result::ok);
+        let fade = PropFade::new(
+            float("fademindist").unwrap_or(-1.0),
+            float("fademaxdist").unwrap_or(0.0),
+            float("fadescale").unwrap_or(1.0),
+        );
+        let body = ent
+            .prop_parse::<i32>("body")
+            .and_then(Result::ok)
+            .unwrap_or_default();
+        let animation = ent
+            .prop("DefaultAnim")
+            .or_else(|| ent.prop("defaultanim"))
+            .filter(|sequence| !sequence.is_empty());
+        Some(MapProp {
+            placement,
+            fade,
+            body,
+            animation,
+        })
+    });
+    let static_props = bsp.static_props().map(|prop| MapProp {
+        placement: prop.as_prop_placement(),
+        fade: PropFade::new(
+            prop.fade_min_distance,
+            prop.fade_max_distance,
+            prop.forced_fade_scale,
+        ),
+        // static props always use the default bodygroups and aren't animated
+        body: 0,
+        animation: None,
+    });
 
     if props {
-        let props = load_props(loader, static_props.chain(entity_props), textures)?;
-        models.extend(props);
+        map.props = load_props(loader, static_props.chain(entity_props), textures, &assets)?;
+        map.props.screen_fade = load_screen_fade(&bsp);
+        if let Some(visibility) = map.visibility.as_ref() {
+            // props are assigned to the cluster containing their origin
+            for instance in map
+                .props
+                .models
+                .iter_mut()
+                .flat_map(|model| model.instances.iter_mut())
+            {
+                let origin = instance.transform.w.truncate();
+                instance.cluster = visibility.cluster_at(unmap_coords(origin));
+            }
+        }
     }
-    Ok(models)
+
+    map.sky = load_sky(&bsp, loader, &assets);
+    map.environment_light = load_environment_light(&bsp);
+    map.lights = load_lights(&bsp);
+    map.fog = load_fog(&bsp);
+    map.info = MapInfo::new(&bsp);
+    // without the clusters visible from the sky camera the whole map would be drawn again as the 3d skybox
+    map.sky.camera = map.sky.camera.take().and_then(|mut camera| {
+        let Some(visibility) = map.visibility.as_ref() else {
+            warn!("map has no vis data, skipping the 3d skybox");
+            return None;
+        };
+        camera.visible_clusters =
+            visibility.visible_clusters(visibility.cluster_at(unmap_coords(camera.origin)));
+        if camera.visible_clusters.is_empty() {
+            warn!("sky camera is outside the world, skipping the 3d skybox");
+            return None;
+        }
+        Some(camera)
+    });
+    (map.assets, map.missing) = assets.into_parts();
+    Ok(map)
 }
 
 pub fn map_coords<C: Into<Vec3>>(vec: C) -> Vec3 {
@@ -48,14 +142,54 @@ pub fn map_coords<C: Into<Vec3>>(vec: C) -> Vec3 {
     }
 }
 
+/// Inverse of [`map_coords`]
+pub fn unmap_coords(vec: Vec3) -> Vector {
+    Vector {
+        x: vec.z / UNIT_SCALE,
+        y: vec.x / UNIT_SCALE,
+        z: vec.y / UNIT_SCALE,
+    }
+}
+
 // 1 hammer unit is ~1.905cm
 pub const UNIT_SCALE: f32 = 1.0 / (1.905 * 100.0);
 
+/// Get the version and raw data of an uncompressed lump from the bsp file
+///
+/// For lumps that vbsp doesn't expose (or doesn't keep in file order)
+pub fn lump_data(data: &[u8], lump: usize) -> Option<(u32, &[u8])> {
+    let entry = data.get(8 + lump * 16..8 + (lump + 1) * 16)?;
+    let field = |i: usize| u32::from_le_bytes(entry[i * 4..i * 4 + 4].try_into().unwrap());
+    let (offset, length, version, uncompressed_length) =
+        (field(0) as usize, field(1) as usize, field(2), field(3));
+    if uncompressed_length != 0 {
+        warn!(lump, "compressed lumps are not supported");
+        return None;
+    }
+    data.get(offset..offset + length)
+        .filter(|data| !data.is_empty())
+        .map(|data| (version, data))
+}
+
+#[test]
+fn test_unmap_coords() {
+    let pos = Vector {
+        x: 100.0,
+        y: -50.0,
+        z: 20.0,
+    };
+    let round_trip = unmap_coords(map_coords(pos));
+    assert!((round_trip - pos).length_squared() < 0.001);
+}
+
 fn model_to_model(
     models: &[(Handle<vbsp::data::Model>, Vector)],
     loader: &Loader,
     textures: bool,
-) -> CpuModel {
+    lightmap: Option<&LightmapAtlas>,
+    face_clusters: &HashMap<usize, Vec<i16>>,
+    assets: &AssetLog,
+) -> (CpuModel, Vec<Vec<Vec2>>, Vec<Vec<i16>>) {
     let textures: Vec<&str> = if textures {
         let textures: HashSet<&str> = models
             .iter()
@@ -67,78 +201,251 @@ fn model_to_model(
         Vec::new()
     };
 
-    let faces_by_texture: HashMap<&str, _> = models
+    // split the faces by cluster and spatial chunk so the visibility data and frustum can cull them
+    let no_clusters = Vec::new();
+    let faces_by_texture: HashMap<(&str, i16, [i32; 3]), _> = models
         .iter()
-        .flat_map(|(model, origin)| model.faces().map(|face| (face, *origin)))
-        .filter(|(face, _)| face.is_visible())
-        .map(|(face, origin)| (face.texture().name(), (face, origin)))
+        .flat_map(|(model, origin)| {
+            let first_face = model.first_face as usize;
+            model
+                .faces()
+                .enumerate()
+                .map(move |(i, face)| (face, *origin, first_face + i))
+        })
+        .filter(|(face, _, _)| face.is_visible())
+        .map(|(face, origin, index)| {
+            let clusters = face_clusters.get(&index).unwrap_or(&no_clusters);
+            let cluster = clusters.first().copied().unwrap_or(-1);
+            let chunk = face_chunk(&face, origin);
+            (
+                (face.texture().name(), cluster, chunk),
+                (face, origin, clusters),
+            )
+        })
         .into_group_map();
 
-    let geometries: Vec<_> = faces_by_texture
-        .into_values()
-        .map(|faces| {
-            let positions: Vec<_> = faces
-                .iter()
-                .flat_map(|(face, origin)| face.vertex_positions().map(|pos| pos + *origin))
-                .map(map_coords)
-                .collect();
+    let material_index = |texture: &str| {
+        textures
+            .iter()
+            .enumerate()
+            .find_map(|(i, tex)| (*tex == texture).then_some(i))
+    };
 
-            let uvs: Vec<_> = faces
+    let (geometries, lightmap_uvs, primitive_clusters): (Vec<_>, Vec<_>, Vec<_>) = faces_by_texture
+        .into_iter()
+        .flat_map(|((texture, _, _), faces)| {
+            let mut clusters: Vec<i16> = faces
                 .iter()
-                .flat_map(|(face, _)| {
-                    let texture = face.texture();
-                    face.vertex_positions()
-                        .map(move |position| texture.uv(position))
-                })
-                .map(|uv| uv.into())
+                .flat_map(|(_, _, clusters)| clusters.iter().copied())
                 .collect();
+            clusters.sort_unstable();
+            clusters.dedup();
 
-            let mut mesh = TriMesh {
-                positions: Positions::F32(positions),
-                uvs: Some(uvs),
-                ..Default::default()
-            };
-            mesh.compute_normals();
-            mesh.compute_tangents();
+            let (displacements, faces): (Vec<_>, Vec<_>) = faces
+                .into_iter()
+                .map(|(face, origin, _)| (face, origin))
+                .partition(|(face, _)| face.displacement_index().is_some());
+            let material_index = material_index(texture);
 
-            let texture = faces.first().unwrap().0.texture().name();
-            let material_index = textures
-                .iter()
-                .enumerate()
-                .find_map(|(i, tex)| (*tex == texture).then_some(i));
-
-            Primitive {
-                name: "".to_string(),
-                transformation: Matrix4::from_scale(1.0),
-                animations: vec![],
-                geometry: Geometry::Triangles(mesh),
-                material_index,
-            }
+            [
+                (!faces.is_empty()).then(|| faces_to_mesh(&faces, lightmap)),
+                (!displacements.is_empty())
+                    .then(|| displacements_to_mesh(&displacements, lightmap)),
+            ]
+            .into_iter()
+            .flatten()
+            .map(move |(mesh, lightmap_uvs)| {
+                let primitive = Primitive {
+                    name: texture.to_string(),
+                    transformation: Matrix4::from_scale(1.0),
+                    animations: vec![],
+                    geometry: Geometry::Triangles(mesh),
+                    material_index,
+                };
+                (primitive, lightmap_uvs, clusters.clone())
+            })
         })
-        .collect();
+        .multiunzip();
 
     let materials: Vec<_> = textures
         .iter()
-        .map(|texture| load_material_fallback(texture, loader))
+        .map(|texture| load_material_fallback(texture, loader, assets))
         .map(convert_material)
         .collect();
 
-    CpuModel {
+    let model = CpuModel {
         name: "bsp".to_string(),
         geometries,
         materials,
+    };
+    (model, lightmap_uvs, primitive_clusters)
+}
+
+/// Size of the spatial chunks the world is split in, in hammer units
+const CHUNK_SIZE: f32 = 1024.0;
+
+fn face_chunk(face: &Handle<Face>, origin: Vector) -> [i32; 3] {
+    let (sum, count) = face
+        .vertex_positions()
+        .fold((Vector::default(), 0.0f32), |(sum, count), pos| {
+            (sum + pos, count + 1.0)
+        });
+    let center = sum * (1.0 / count.max(1.0)) + origin;
+    [center.x, center.y, center.z].map(|coord| (coord / CHUNK_SIZE).floor() as i32)
+}
+
+fn faces_to_mesh(
+    faces: &[(Handle<Face>, Vector)],
+    lightmap: Option<&LightmapAtlas>,
+) -> (TriMesh, Vec<Vec2>) {
+    let positions: Vec<_> = faces
+        .iter()
+        .flat_map(|(face, origin)| face.vertex_positions().map(|pos| pos + *origin))
+        .map(map_coords)
+        .collect();
+
+    let uvs: Vec<_> = faces
+        .iter()
+        .flat_map(|(face, _)| {
+            let texture = face.texture();
+            face.vertex_positions()
+                .map(move |position| texture.uv(position))
+        })
+        .map(|uv| uv.into())
+        .collect();
+
+    let lightmap_uvs: Vec<_> = match lightmap {
+        Some(lightmap) => faces
+            .iter()
+            .flat_map(|(face, _)| {
+                face.vertex_positions()
+                    .map(move |position| lightmap.uv(face, position))
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let mut mesh = TriMesh {
+        positions: Positions::F32(positions),
+        uvs: Some(uvs),
+        ..Default::default()
+    };
+    mesh.compute_normals();
+    mesh.compute_tangents();
+    (mesh, lightmap_uvs)
+}
+
+/// Build a smooth, indexed mesh from the vertex grids of displacement faces
+///
+/// The displacement alpha is stored in the alpha channel of the vertex colors
+fn displacements_to_mesh(
+    faces: &[(Handle<Face>, Vector)],
+    lightmap: Option<&LightmapAtlas>,
+) -> (TriMesh, Vec<Vec2>) {
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    let mut lightmap_uvs = Vec::new();
+    let mut colors = Vec::new();
+    let mut indices = Vec::new();
+
+    for (face, origin) in faces {
+        let Some(displacement) = face.displacement() else {
+            continue;
+        };
+        let texture = face.texture();
+        let start = positions.len() as u32;
+        let steps = 2u32.pow(displacement.power as u32);
+
+        // texture coordinates are based on the un-displaced face
+        let base_positions: Vec<Vector> = displacement
+            .displacement_vertices()
+            .zip(displacement.displaced_vertices())
+            .map(|(vertex, position)| {
+                positions.push(map_coords(position + *origin));
+                colors.push(Srgba::new(
+                    255,
+                    255,
+                    255,
+                    vertex.alpha.clamp(0.0, 255.0) as u8,
+                ));
+                position - vertex.displacement()
+            })
+            .collect();
+        uvs.extend(
+            base_positions
+                .iter()
+                .map(|position| Vec2::from(texture.uv(*position))),
+        );
+        if let Some(lightmap) = lightmap {
+            lightmap_uvs.extend(
+                base_positions
+                    .iter()
+                    .map(|position| lightmap.uv(face, *position)),
+            );
+        }
+
+        // vertices are stored in rows along the first edge of the face
+        let index = |x: u32, y: u32| x * (steps + 1) + y;
+
+        // pick the winding order that makes the triangles face the same way as the face
+        let corner = |x: u32, y: u32| Vec3::from(base_positions[index(x, y) as usize]);
+        let grid_normal = (corner(1, 0) - corner(0, 0)).cross(corner(0, 1) - corner(0, 0));
+        let flip = grid_normal.dot(Vec3::from(face.normal())) < 0.0;
+
+        for x in 0..steps {
+            for y in 0..steps {
+                let quad = [
+                    [index(x, y), index(x + 1, y), index(x, y + 1)],
+                    [index(x + 1, y), index(x + 1, y + 1), index(x, y + 1)],
+                ];
+                for [a, b, c] in quad {
+                    if flip {
+                        indices.extend([start + c, start + b, start + a]);
+                    } else {
+                        indices.extend([start + a, start + b, start + c]);
+                    }
+                }
+            }
+        }
     }
+
+    let mut mesh = TriMesh {
+        positions: Positions::F32(positions),
+        indices: Indices::U32(indices),
+        uvs: Some(uvs),
+        colors: Some(colors),
+        ..Default::default()
+    };
+    mesh.compute_normals();
+    mesh.compute_tangents();
+    (mesh, lightmap_uvs)
 }
 
-fn load_world(data: &[u8], loader: &mut Loader, textures: bool) -> Result<(CpuModel, Bsp), Error> {
+fn load_world(
+    data: &[u8],
+    loader: &mut Loader,
+    textures: bool,
+    assets: &AssetLog,
+) -> Result<(Map, Bsp), Error> {
     let bsp = Bsp::read(data)?;
 
-    loader.add_source(bsp.pack.clone().into_zip());
+    let pack = bsp.pack.clone().into_zip();
+    let packed = pack
+        .lock()
+        .unwrap()
+        .file_names()
+        .map(str::to_ascii_lowercase)
+        .collect();
+    loader.add_source(pack);
 
     let world_model = bsp
         .models()
         .next()
         .ok_or(Error::Other("No world model".into()))?;
+    let bounds = AxisAlignedBoundingBox::new_with_positions(&[
+        map_coords(world_model.mins),
+        map_coords(world_model.maxs),
+    ]);
 
     let mut models: Vec<_> = bsp
         .entities
@@ -163,6 +470,38 @@ fn load_world(data: &[u8], loader: &mut Loader, textures: bool) -> Result<(CpuMo
         },
     ));
 
-    let world_model = model_to_model(&models, loader, textures);
-    Ok((world_model, bsp))
+    let atlas = LightmapAtlas::new(data, &bsp);
+    let mut visibility = Visibility::new(data, &bsp);
+    let (world_model, lightmap_uvs, clusters) = model_to_model(
+        &models,
+        loader,
+        textures,
+        atlas.as_ref(),
+        &face_clusters(&bsp),
+        assets,
+    );
+    let lightmap = atlas.map(|atlas| Lightmap {
+        atlas: atlas.into_texture(),
+        uvs: lightmap_uvs,
+    });
+    if let Some(visibility) = visibility.as_mut() {
+        visibility.primitive_clusters.push(clusters);
+    }
+    let map = Map {
+        models: vec![world_model],
+        props: Props::default(),
+        bounds,
+        lightmap,
+        visibility,
+        sky: Sky::default(),
+        environment_light: None,
+        lights: Vec::new(),
+        fog: None,
+        info: MapInfo::default(),
+        assets: BTreeSet::new(),
+        missing: BTreeMap::new(),
+        packed,
+        entity_files: entity_files(&bsp),
+    };
+    Ok((map, bsp))
 }
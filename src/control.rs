@@ -1,8 +1,10 @@
+use crate::bsp::UNIT_SCALE;
 use crate::wrapping::Wrapping;
 use crate::DemoInfo;
 use splines::Spline;
 use std::ops::RangeInclusive;
-use three_d::egui::{Slider, Ui};
+use tf_demo_parser::demo::message::packetentities::EntityId;
+use three_d::egui::{ScrollArea, Slider, Ui};
 use three_d::*;
 use tracing::{debug, info};
 
@@ -18,6 +20,19 @@ pub trait Control {
     fn ui(&mut self, _ui: &mut Ui) {}
 
     fn post_ui(&mut self, _time: f64) {}
+
+    /// The current demo position, for controls that play back a demo
+    fn playback(&self) -> Option<DemoPlayback> {
+        None
+    }
+}
+
+/// Playback position of a demo, for drawing the entities from the demo
+#[derive(Debug, Clone, Copy)]
+pub struct DemoPlayback {
+    pub tick: f64,
+    /// The player the camera is looking through, which isn't drawn
+    pub pov_player: Option<EntityId>,
 }
 
 pub struct FirstPerson {
@@ -133,12 +148,53 @@ impl DebugToggle {
     }
 }
 
+/// How the demo camera is placed relative to the followed player
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CameraMode {
+    /// Looking through the eyes of the followed player
+    FirstPerson,
+    /// Behind and above the followed player
+    ThirdPerson,
+    /// Detached from the players, moved with the keyboard and mouse
+    Free,
+}
+
+impl CameraMode {
+    fn next(self) -> Self {
+        match self {
+            CameraMode::FirstPerson => CameraMode::ThirdPerson,
+            CameraMode::ThirdPerson => CameraMode::Free,
+            CameraMode::Free => CameraMode::FirstPerson,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            CameraMode::FirstPerson => "first person",
+            CameraMode::ThirdPerson => "third person",
+            CameraMode::Free => "free",
+        }
+    }
+}
+
+const FREE_SPEED: f32 = 0.1;
+/// Distance behind and above the followed player in third person, in hammer units
+const CHASE_DISTANCE: f32 = 120.0;
+const CHASE_HEIGHT: f32 = 40.0;
+
 pub struct DemoCamera {
     demo: DemoInfo,
+    mode: CameraMode,
+    /// Movement for the free camera
+    free: FirstPerson,
+    /// The player the camera is looking through
+    followed: Option<EntityId>,
     positions: Spline<f32, Vec3>,
     pitch: Spline<f32, Wrapping<-180, 180>>,
     yaw: Spline<f32, Wrapping<-180, 180>>,
     playing: bool,
+    /// The tick currently shown
+    tick: f64,
     start_tick: f64,
     playback_start_time: f64,
     ui_tick: u32,
@@ -153,12 +209,16 @@ impl Control for DemoCamera {
         &mut self,
         camera: &mut Camera,
         events: &mut [Event],
-        _elapsed_time: f64,
+        elapsed_time: f64,
         accumulated_time: f64,
     ) -> bool {
         let mut change = false;
         for event in events.iter_mut() {
             if let Event::Text(text) = event {
+                if text == "c" {
+                    change = true;
+                    self.set_mode(self.mode.next());
+                }
                 if text == "p" {
                     change = true;
                     self.playing = !self.playing;
@@ -187,22 +247,61 @@ impl Control for DemoCamera {
                     "playing tick"
                 );
                 let data = self.get_tick(tick);
-                self.apply_view(camera, data.position, data.angles[0], data.angles[1]);
+                match self.mode {
+                    CameraMode::FirstPerson => {
+                        self.apply_view(camera, data.position, data.yaw, data.pitch)
+                    }
+                    CameraMode::ThirdPerson => self.apply_chase_view(camera, data),
+                    CameraMode::Free => {}
+                }
+                self.tick = tick;
             }
             self.force_update = false;
         }
 
+        if self.mode == CameraMode::Free {
+            change |= self
+                .free
+                .handle(camera, events, elapsed_time, accumulated_time);
+        }
+
         self.playing | change
     }
 
     fn ui(&mut self, ui: &mut Ui) {
         ui.label("Playback");
         ui.label("  toggle playback with <p>");
+        ui.label(format!("  camera: {}, switch with <c>", self.mode.name()));
+        if self.mode == CameraMode::Free {
+            ui.label("  move with WASD, look with left drag");
+        }
         self.last_ui_tick = self.ui_tick;
         self.last_speed = self.speed;
         let range = self.tick_range();
         ui.add(Slider::new(&mut self.ui_tick, range).text("tick"));
         ui.add(Slider::new(&mut self.speed, 0.1..=10.0).text("speed"));
+
+        ui.label("Players");
+        let tick = self.tick;
+        let mut followed = self.followed;
+        ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+            for player in self.demo.entities.players.iter() {
+                let state = player.track.state(tick);
+                let text = if state.team.is_player() {
+                    format!("{} ({:?} {:?})", player.track.name, state.team, state.class)
+                } else {
+                    format!("{} ({:?})", player.track.name, state.team)
+                };
+                let selected = followed == Some(player.track.entity);
+                if ui.selectable_label(selected, text).clicked() {
+                    followed = Some(player.track.entity);
+                }
+            }
+        });
+        if followed != self.followed {
+            self.followed = followed;
+            self.force_update = true;
+        }
     }
 
     fn post_ui(&mut self, time: f64) {
@@ -210,6 +309,16 @@ impl Control for DemoCamera {
             self.set_tick(self.ui_tick, time);
         }
     }
+
+    fn playback(&self) -> Option<DemoPlayback> {
+        Some(DemoPlayback {
+            tick: self.tick,
+            pov_player: match self.mode {
+                CameraMode::FirstPerson => self.followed,
+                _ => None,
+            },
+        })
+    }
 }
 
 impl DemoCamera {
@@ -218,11 +327,15 @@ impl DemoCamera {
         let pitch = Spline::from_vec(demo.positions.pitch.clone());
         let yaw = Spline::from_vec(demo.positions.yaw.clone());
         DemoCamera {
+            followed: demo.player,
             demo,
+            mode: CameraMode::FirstPerson,
+            free: FirstPerson::new(FREE_SPEED),
             positions,
             pitch,
             yaw,
             playing: false,
+            tick: 0.0,
             start_tick: 0.0,
             playback_start_time: 0.0,
             ui_tick: 0,
@@ -245,6 +358,23 @@ impl DemoCamera {
         camera.set_view(position, target, vec3(0.0, 1.0, 0.0))
     }
 
+    /// Look at the player from behind and above, facing the direction they are turned to
+    fn apply_chase_view(&self, camera: &mut Camera, data: TickData) {
+        let (distance, height) = (CHASE_DISTANCE * UNIT_SCALE, CHASE_HEIGHT * UNIT_SCALE);
+        let forward = (Mat4::from_angle_y(degrees(data.yaw)) * vec4(0.0, 0.0, 1.0, 0.0)).truncate();
+        let position = data.position - forward * distance + vec3(0.0, height, 0.0);
+        camera.set_view(position, data.position, vec3(0.0, 1.0, 0.0))
+    }
+
+    fn set_mode(&mut self, mode: CameraMode) {
+        if mode == CameraMode::Free {
+            // start without any movement keys held from before
+            self.free = FirstPerson::new(FREE_SPEED);
+        }
+        self.mode = mode;
+        self.force_update = true;
+    }
+
     fn tick_range(&self) -> RangeInclusive<u32> {
         u32::from(self.demo.start_tick)..=self.demo.ticks + u32::from(self.demo.start_tick)
     }
@@ -256,15 +386,29 @@ impl DemoCamera {
     }
 
     fn get_tick(&self, tick: f64) -> TickData {
+        // the demo has the exact view of the player that recorded it, other players are approximated
+        if self.followed != self.demo.pov_player {
+            let player = self
+                .demo
+                .entities
+                .players
+                .iter()
+                .find(|player| Some(player.track.entity) == self.followed);
+            if let Some(player) = player {
+                return TickData {
+                    position: player.eye_position(tick).unwrap_or(vec3(0.0, 0.0, 0.0)),
+                    pitch: player.pitch(tick),
+                    yaw: player.yaw(tick),
+                };
+            }
+        }
         TickData {
             position: self
                 .positions
                 .clamped_sample(tick as f32)
                 .unwrap_or(vec3(0.0, 0.0, 0.0)),
-            angles: [
-                self.pitch.clamped_sample(tick as f32).unwrap_or_default().0,
-                self.yaw.clamped_sample(tick as f32).unwrap_or_default().0,
-            ],
+            pitch: self.pitch.clamped_sample(tick as f32).unwrap_or_default().0,
+            yaw: self.yaw.clamped_sample(tick as f32).unwrap_or_default().0,
         }
     }
 }
@@ -316,5 +460,6 @@ fn apply_camera_action(camera: &mut Camera, control_type: CameraAction, x: f64)
 #[derive(Copy, Clone, Debug)]
 pub struct TickData {
     pub position: Vec3,
-    pub angles: [f32; 2],
+    pub pitch: f32,
+    pub yaw: f32,
 }
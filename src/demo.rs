@@ -1,15 +1,18 @@
 use crate::bsp::{map_coords, UNIT_SCALE};
 use crate::wrapping::Wrapping;
 use crate::Error;
-use splines::{Interpolation, Key};
+use splines::{Interpolation, Key, Spline};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::Path;
+use std::rc::Rc;
 use tf_demo_parser::demo::data::{DemoTick, UserInfo};
 use tf_demo_parser::demo::header::Header;
-use tf_demo_parser::demo::message::packetentities::EntityId;
+use tf_demo_parser::demo::message::packetentities::{EntityId, PacketEntity, UpdateType};
 use tf_demo_parser::demo::message::Message;
 use tf_demo_parser::demo::packet::message::MessagePacketMeta;
 use tf_demo_parser::demo::packet::stringtable::StringTableEntry;
+use tf_demo_parser::demo::parser::analyser::{Class, Team};
 use tf_demo_parser::demo::parser::MessageHandler;
 use tf_demo_parser::demo::sendprop::SendPropIdentifier;
 use tf_demo_parser::demo::vector::{Vector, VectorXY};
@@ -19,7 +22,13 @@ use three_d::{vec3, Vec3};
 pub struct DemoInfo {
     pub ticks: u32,
     pub map: String,
+    /// The view of the player that recorded the demo
     pub positions: Positions,
+    pub entities: DemoEntities,
+    /// The player the camera follows at the start
+    pub player: Option<EntityId>,
+    /// The player that recorded the demo
+    pub pov_player: Option<EntityId>,
     pub start_tick: DemoTick,
     pub time_per_tick: f64,
 }
@@ -30,12 +39,16 @@ impl DemoInfo {
         let demo = Demo::new(&file);
         let parser =
             DemoParser::new_with_analyser(demo.get_stream(), PovAnalyzer::new(name.into()));
-        let (header, (positions, start_tick, interval_per_tick)) = parser.parse()?;
+        let (header, (positions, entities, player, pov_player, start_tick, interval_per_tick)) =
+            parser.parse()?;
 
         Ok(DemoInfo {
             ticks: header.ticks,
             map: header.map,
             positions,
+            entities,
+            player,
+            pov_player,
             start_tick,
             time_per_tick: interval_per_tick as f64,
         })
@@ -49,21 +62,353 @@ pub struct Positions {
     pub yaw: Vec<Key<f32, Wrapping<-180, 180>>>,
 }
 
+/// The players, projectiles and buildings in the demo
+#[derive(Default)]
+pub struct DemoEntities {
+    pub players: Rc<[PlayerPath]>,
+    pub objects: Vec<DemoObject>,
+}
+
+/// Position, angles and state of a player entity over the demo
+pub struct PlayerTrack {
+    pub entity: EntityId,
+    pub name: String,
+    /// Position of the player's feet
+    pub positions: Vec<Key<f32, Vec3>>,
+    pub pitch: Vec<Key<f32, Wrapping<-180, 180>>>,
+    pub yaw: Vec<Key<f32, Wrapping<-180, 180>>>,
+    /// The state of the player from the tick it changed
+    pub states: Vec<(u32, PlayerState)>,
+    position: Vector,
+    state: PlayerState,
+}
+
+/// A player with the splines to sample its position and angles at any tick
+pub struct PlayerPath {
+    pub track: PlayerTrack,
+    positions: Spline<f32, Vec3>,
+    pitch: Spline<f32, Wrapping<-180, 180>>,
+    yaw: Spline<f32, Wrapping<-180, 180>>,
+}
+
+impl PlayerPath {
+    fn new(track: PlayerTrack) -> Self {
+        PlayerPath {
+            positions: Spline::from_vec(track.positions.clone()),
+            pitch: Spline::from_vec(track.pitch.clone()),
+            yaw: Spline::from_vec(track.yaw.clone()),
+            track,
+        }
+    }
+
+    /// Position of the player's feet at a tick
+    pub fn position(&self, tick: f64) -> Option<Vec3> {
+        self.positions.clamped_sample(tick as f32)
+    }
+
+    /// Position of the player's eyes at a tick, from the standing view height of their class
+    pub fn eye_position(&self, tick: f64) -> Option<Vec3> {
+        let height = view_height(self.track.state(tick).class) * UNIT_SCALE;
+        self.position(tick)
+            .map(|position| position + vec3(0.0, height, 0.0))
+    }
+
+    /// Pitch of the player's view at a tick
+    pub fn pitch(&self, tick: f64) -> f32 {
+        self.pitch.clamped_sample(tick as f32).unwrap_or_default().0
+    }
+
+    /// Yaw of the player's view at a tick
+    pub fn yaw(&self, tick: f64) -> f32 {
+        self.yaw.clamped_sample(tick as f32).unwrap_or_default().0
+    }
+}
+
+/// Standing view height of a class in hammer units
+fn view_height(class: Class) -> f32 {
+    match class {
+        Class::Scout => 65.0,
+        Class::Heavy | Class::Medic | Class::Sniper | Class::Spy => 75.0,
+        _ => 68.0,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PlayerState {
+    pub team: Team,
+    pub class: Class,
+    pub alive: bool,
+    /// Whether the entity is part of the snapshot, players outside the recording player's pvs aren't
+    pub in_pvs: bool,
+}
+
+impl PlayerTrack {
+    fn new(entity: EntityId) -> Self {
+        PlayerTrack {
+            entity,
+            name: String::new(),
+            positions: Vec::new(),
+            pitch: Vec::new(),
+            yaw: Vec::new(),
+            states: Vec::new(),
+            position: Vector::default(),
+            state: PlayerState::default(),
+        }
+    }
+
+    /// The state of the player at a tick
+    pub fn state(&self, tick: f64) -> PlayerState {
+        let index = self
+            .states
+            .partition_point(|(start, _)| *start as f64 <= tick);
+        index
+            .checked_sub(1)
+            .map(|index| self.states[index].1)
+            .unwrap_or_default()
+    }
+
+    fn update(&mut self, entity: &PacketEntity, tick: DemoTick, state: &ParserState) {
+        const TEAM: SendPropIdentifier = SendPropIdentifier::new("DT_BaseEntity", "m_iTeamNum");
+        const CLASS: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFPlayerClassShared", "m_iClass");
+        const LIFE_STATE: SendPropIdentifier =
+            SendPropIdentifier::new("DT_BasePlayer", "m_lifeState");
+        const LOCAL_ORIGIN: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFLocalPlayerExclusive", "m_vecOrigin");
+        const NON_LOCAL_ORIGIN: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_vecOrigin");
+        const LOCAL_ORIGIN_Z: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFLocalPlayerExclusive", "m_vecOrigin[2]");
+        const NON_LOCAL_ORIGIN_Z: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_vecOrigin[2]");
+        const LOCAL_PITCH: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFLocalPlayerExclusive", "m_angEyeAngles[0]");
+        const NON_LOCAL_PITCH: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_angEyeAngles[0]");
+        const LOCAL_YAW: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFLocalPlayerExclusive", "m_angEyeAngles[1]");
+        const NON_LOCAL_YAW: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_angEyeAngles[1]");
+
+        let tick = u32::from(tick);
+        let old_position = self.position;
+        let mut player_state = self.state;
+        player_state.in_pvs = !matches!(entity.update_type, UpdateType::Leave | UpdateType::Delete);
+
+        for prop in entity.props(state) {
+            match prop.identifier {
+                TEAM => {
+                    player_state.team = Team::new(i64::try_from(&prop.value).unwrap_or_default())
+                }
+                CLASS => {
+                    player_state.class = Class::new(i64::try_from(&prop.value).unwrap_or_default())
+                }
+                LIFE_STATE => {
+                    player_state.alive = i64::try_from(&prop.value).unwrap_or_default() == 0
+                }
+                LOCAL_ORIGIN | NON_LOCAL_ORIGIN => {
+                    let pos_xy = VectorXY::try_from(&prop.value).unwrap_or_default();
+                    self.position.x = pos_xy.x;
+                    self.position.y = pos_xy.y;
+                }
+                LOCAL_ORIGIN_Z | NON_LOCAL_ORIGIN_Z => {
+                    self.position.z = f32::try_from(&prop.value).unwrap_or_default()
+                }
+                LOCAL_PITCH | NON_LOCAL_PITCH => push_key(
+                    &mut self.pitch,
+                    tick,
+                    Wrapping(f32::try_from(&prop.value).unwrap_or_default()),
+                    Interpolation::Linear,
+                ),
+                LOCAL_YAW | NON_LOCAL_YAW => push_key(
+                    &mut self.yaw,
+                    tick,
+                    Wrapping(f32::try_from(&prop.value).unwrap_or_default()),
+                    Interpolation::Linear,
+                ),
+                _ => {}
+            }
+        }
+
+        if self.position != old_position || self.positions.is_empty() {
+            // players teleport when respawning, so the positions aren't smoothed
+            push_key(
+                &mut self.positions,
+                tick,
+                map_coords(<[f32; 3]>::from(self.position)),
+                Interpolation::Linear,
+            );
+        }
+        if player_state != self.state || self.states.is_empty() {
+            self.state = player_state;
+            self.states.push((tick, player_state));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Rocket,
+    Pipe,
+    Sticky,
+    Arrow,
+    Flare,
+    Sentry,
+    Dispenser,
+    Teleporter,
+}
+
+impl ObjectKind {
+    pub const ALL: [ObjectKind; 8] = [
+        ObjectKind::Rocket,
+        ObjectKind::Pipe,
+        ObjectKind::Sticky,
+        ObjectKind::Arrow,
+        ObjectKind::Flare,
+        ObjectKind::Sentry,
+        ObjectKind::Dispenser,
+        ObjectKind::Teleporter,
+    ];
+
+    /// The kind of object for an entity's server class
+    fn from_class(class: &str) -> Option<Self> {
+        Some(match class {
+            "CTFProjectile_Rocket" | "CTFProjectile_SentryRocket" => ObjectKind::Rocket,
+            // stickies share the class with pipes, they're told apart by the projectile type
+            "CTFGrenadePipebombProjectile" => ObjectKind::Pipe,
+            "CTFProjectile_Arrow" | "CTFProjectile_HealingBolt" => ObjectKind::Arrow,
+            "CTFProjectile_Flare" => ObjectKind::Flare,
+            "CObjectSentrygun" => ObjectKind::Sentry,
+            "CObjectDispenser" => ObjectKind::Dispenser,
+            "CObjectTeleporter" => ObjectKind::Teleporter,
+            _ => return None,
+        })
+    }
+}
+
+/// A projectile or building over its lifetime
+pub struct DemoObject {
+    pub kind: ObjectKind,
+    pub team: Team,
+    /// The tick the object appeared at
+    pub start: u32,
+    /// The tick the object was removed at, `None` if it's there until the end of the demo
+    pub end: Option<u32>,
+    pub positions: Vec<Key<f32, Vec3>>,
+    /// The pitch, yaw and roll of the object from the tick they changed
+    pub angles: Vec<(u32, [f32; 3])>,
+}
+
+impl DemoObject {
+    fn new(kind: ObjectKind, tick: u32) -> Self {
+        DemoObject {
+            kind,
+            team: Team::Other,
+            start: tick,
+            end: None,
+            positions: Vec::new(),
+            angles: Vec::new(),
+        }
+    }
+
+    pub fn is_alive(&self, tick: f64) -> bool {
+        self.start as f64 <= tick && self.end.is_none_or(|end| tick < end as f64)
+    }
+
+    /// The angles of the object at a tick
+    pub fn angles(&self, tick: f64) -> [f32; 3] {
+        let index = self
+            .angles
+            .partition_point(|(start, _)| *start as f64 <= tick);
+        index
+            .checked_sub(1)
+            .or((!self.angles.is_empty()).then_some(0))
+            .map(|index| self.angles[index].1)
+            .unwrap_or_default()
+    }
+
+    fn update(&mut self, entity: &PacketEntity, tick: u32, state: &ParserState) {
+        const TEAM: SendPropIdentifier = SendPropIdentifier::new("DT_BaseEntity", "m_iTeamNum");
+        const ORIGIN: SendPropIdentifier = SendPropIdentifier::new("DT_BaseEntity", "m_vecOrigin");
+        const ROCKET_ORIGIN: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFBaseRocket", "m_vecOrigin");
+        const GRENADE_ORIGIN: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFWeaponBaseGrenadeProj", "m_vecOrigin");
+        const ROTATION: SendPropIdentifier =
+            SendPropIdentifier::new("DT_BaseEntity", "m_angRotation");
+        const ROCKET_ROTATION: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFBaseRocket", "m_angRotation");
+        const GRENADE_ROTATION: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFWeaponBaseGrenadeProj", "m_angRotation");
+        const PIPE_TYPE: SendPropIdentifier =
+            SendPropIdentifier::new("DT_TFProjectile_Pipebomb", "m_iType");
+
+        for prop in entity.props(state) {
+            match prop.identifier {
+                TEAM => self.team = Team::new(i64::try_from(&prop.value).unwrap_or_default()),
+                ORIGIN | ROCKET_ORIGIN | GRENADE_ORIGIN => {
+                    let origin = Vector::try_from(&prop.value).unwrap_or_default();
+                    let position = map_coords(<[f32; 3]>::from(origin));
+                    push_key(&mut self.positions, tick, position, Interpolation::Linear);
+                }
+                ROTATION | ROCKET_ROTATION | GRENADE_ROTATION => {
+                    let angles = Vector::try_from(&prop.value).unwrap_or_default();
+                    self.angles.push((tick, [angles.x, angles.y, angles.z]));
+                }
+                // 1 and 2 are the regular and practice stickies
+                PIPE_TYPE if self.kind == ObjectKind::Pipe => {
+                    if matches!(i64::try_from(&prop.value), Ok(1 | 2)) {
+                        self.kind = ObjectKind::Sticky;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Add a key at a tick, replacing the last key if it's from the same tick
+///
+/// Keys can't share a tick, the interpolation would divide by zero.
+fn push_key<V>(
+    keys: &mut Vec<Key<f32, V>>,
+    tick: u32,
+    value: V,
+    interpolation: Interpolation<f32, V>,
+) {
+    match keys.last_mut() {
+        Some(key) if key.t == tick as f32 => key.value = value,
+        _ => keys.push(Key::new(tick as f32, value, interpolation)),
+    }
+}
+
 struct PovAnalyzer {
-    last_position: Vector,
     view_offset: f32,
     positions: Positions,
     name: String,
+    /// The player to follow, the first player matching `name`
     player: Option<EntityId>,
+    /// The player that recorded the demo
+    pov_player: Option<EntityId>,
     start_tick: DemoTick,
     pov_name: String,
-    is_pov: bool,
-    last_tick: DemoTick,
     last_pov_tick: DemoTick,
+    names: HashMap<EntityId, String>,
+    players: BTreeMap<EntityId, PlayerTrack>,
+    objects: Vec<DemoObject>,
+    /// Index into `objects` of the object currently using an entity id
+    active_objects: HashMap<EntityId, usize>,
 }
 
 impl MessageHandler for PovAnalyzer {
-    type Output = (Positions, DemoTick, f32);
+    type Output = (
+        Positions,
+        DemoEntities,
+        Option<EntityId>,
+        Option<EntityId>,
+        DemoTick,
+        f32,
+    );
 
     fn does_handle(message_type: MessageType) -> bool {
         matches!(message_type, MessageType::PacketEntities)
@@ -76,73 +421,40 @@ impl MessageHandler for PovAnalyzer {
         }
     }
 
-    fn handle_message(&mut self, message: &Message, tick: DemoTick, _state: &ParserState) {
-        if tick > self.last_tick {
-            self.last_tick = tick;
-            const NON_LOCAL_ORIGIN: SendPropIdentifier =
-                SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_vecOrigin");
-            const NON_LOCAL_ORIGIN_Z: SendPropIdentifier =
-                SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_vecOrigin[2]");
-            const NON_LOCAL_PITCH_ANGLES: SendPropIdentifier =
-                SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_angEyeAngles[0]");
-            const NON_LOCAL_YAW_ANGLES: SendPropIdentifier =
-                SendPropIdentifier::new("DT_TFNonLocalPlayerExclusive", "m_angEyeAngles[1]");
-            const VIEW_OFFSET: SendPropIdentifier =
-                SendPropIdentifier::new("DT_LocalPlayerExclusive", "m_vecViewOffset[2]");
-
-            let old_pos = self.last_position;
-            let old_offset = self.view_offset;
+    fn handle_message(&mut self, message: &Message, tick: DemoTick, state: &ParserState) {
+        const VIEW_OFFSET: SendPropIdentifier =
+            SendPropIdentifier::new("DT_LocalPlayerExclusive", "m_vecViewOffset[2]");
 
-            if let (Message::PacketEntities(message), Some(player_id)) = (message, self.player) {
-                if self.start_tick == 0 {
-                    self.start_tick = tick;
-                }
-                for entity in &message.entities {
-                    if entity.entity_index == player_id {
-                        for prop in &entity.props {
-                            match prop.identifier {
-                                NON_LOCAL_ORIGIN => {
-                                    let pos_xy =
-                                        VectorXY::try_from(&prop.value).unwrap_or_default();
-                                    self.last_position.x = pos_xy.x;
-                                    self.last_position.y = pos_xy.y;
-                                }
-                                NON_LOCAL_ORIGIN_Z => {
-                                    self.last_position.z =
-                                        f32::try_from(&prop.value).unwrap_or_default()
-                                }
-                                NON_LOCAL_PITCH_ANGLES => {
-                                    self.positions.pitch.push(Key::new(
-                                        u32::from(tick) as f32,
-                                        Wrapping(f32::try_from(&prop.value).unwrap_or_default()),
-                                        Interpolation::Linear,
-                                    ));
-                                }
-                                NON_LOCAL_YAW_ANGLES => {
-                                    self.positions.yaw.push(Key::new(
-                                        u32::from(tick) as f32,
-                                        Wrapping(f32::try_from(&prop.value).unwrap_or_default()),
-                                        Interpolation::Linear,
-                                    ));
-                                }
-                                VIEW_OFFSET => {
-                                    self.view_offset =
-                                        f32::try_from(&prop.value).unwrap_or_default() * UNIT_SCALE;
-                                }
-                                _ => {}
-                            }
-                        }
+        let Message::PacketEntities(message) = message else {
+            return;
+        };
+        if self.start_tick == 0 {
+            self.start_tick = tick;
+        }
+        for entity in &message.entities {
+            let class = state
+                .server_classes
+                .get(usize::from(entity.server_class))
+                .map(|class| class.name.as_str())
+                .unwrap_or_default();
+            if class == "CTFPlayer" {
+                self.players
+                    .entry(entity.entity_index)
+                    .or_insert_with(|| PlayerTrack::new(entity.entity_index))
+                    .update(entity, tick, state);
+                // the view offset is only sent for the player that recorded the demo
+                if Some(entity.entity_index) == self.pov_player {
+                    if let Some(prop) = entity
+                        .props
+                        .iter()
+                        .find(|prop| prop.identifier == VIEW_OFFSET)
+                    {
+                        self.view_offset =
+                            f32::try_from(&prop.value).unwrap_or_default() * UNIT_SCALE;
                     }
                 }
-            }
-
-            if (self.last_position != old_pos || old_offset != self.view_offset) && !self.is_pov {
-                let pos = map_coords(<[f32; 3]>::from(self.last_position));
-                self.positions.positions.push(Key::new(
-                    u32::from(tick) as f32,
-                    vec3(pos[0], pos[1] + self.view_offset, pos[2]),
-                    Interpolation::CatmullRom,
-                ));
+            } else if let Some(kind) = ObjectKind::from_class(class) {
+                self.handle_object(entity, kind, u32::from(tick), state);
             }
         }
     }
@@ -154,7 +466,7 @@ impl MessageHandler for PovAnalyzer {
         entry: &StringTableEntry,
         _state: &ParserState,
     ) {
-        if table == "userinfo" && self.player.is_none() {
+        if table == "userinfo" {
             let _ = self.parse_user_info(
                 index as u16,
                 entry.text.as_ref().map(|s| s.as_ref()),
@@ -171,15 +483,15 @@ impl MessageHandler for PovAnalyzer {
     ) {
         if tick != self.last_pov_tick {
             self.last_pov_tick = tick;
-            if self.is_pov {
+            if self.pov_player.is_some() {
                 self.positions.pitch.push(Key::new(
                     u32::from(tick) as f32,
-                    Wrapping(meta.view_angles[0].local_angles.y),
+                    Wrapping(meta.view_angles[0].local_angles.x),
                     Interpolation::Linear,
                 ));
                 self.positions.yaw.push(Key::new(
                     u32::from(tick) as f32,
-                    Wrapping(meta.view_angles[0].local_angles.x),
+                    Wrapping(meta.view_angles[0].local_angles.y),
                     Interpolation::Linear,
                 ));
                 let pos = map_coords(<[f32; 3]>::from(meta.view_angles[0].origin));
@@ -192,9 +504,18 @@ impl MessageHandler for PovAnalyzer {
         }
     }
 
-    fn into_output(self, state: &ParserState) -> Self::Output {
+    fn into_output(mut self, state: &ParserState) -> Self::Output {
+        for (entity, player) in self.players.iter_mut() {
+            player.name = self.names.remove(entity).unwrap_or_default();
+        }
         (
             self.positions,
+            DemoEntities {
+                players: self.players.into_values().map(PlayerPath::new).collect(),
+                objects: self.objects,
+            },
+            self.player,
+            self.pov_player,
             self.start_tick,
             state.demo_meta.interval_per_tick,
         )
@@ -204,16 +525,54 @@ impl MessageHandler for PovAnalyzer {
 impl PovAnalyzer {
     pub fn new(name: String) -> Self {
         PovAnalyzer {
-            last_position: Vector::default(),
             view_offset: 0.0,
             positions: Positions::default(),
             name,
             player: None,
+            pov_player: None,
             start_tick: DemoTick::default(),
             pov_name: String::new(),
-            is_pov: false,
-            last_tick: DemoTick::default(),
             last_pov_tick: DemoTick::default(),
+            names: HashMap::new(),
+            players: BTreeMap::new(),
+            objects: Vec::new(),
+            active_objects: HashMap::new(),
+        }
+    }
+
+    /// Track the lifetime and position of projectiles and buildings
+    ///
+    /// Entity ids get reused, so an entity entering the snapshot always starts a new object
+    fn handle_object(
+        &mut self,
+        entity: &PacketEntity,
+        kind: ObjectKind,
+        tick: u32,
+        state: &ParserState,
+    ) {
+        let active = match entity.update_type {
+            UpdateType::Enter => None,
+            _ => self.active_objects.get(&entity.entity_index).copied(),
+        };
+        if matches!(
+            entity.update_type,
+            UpdateType::Enter | UpdateType::Leave | UpdateType::Delete
+        ) {
+            if let Some(index) = self.active_objects.remove(&entity.entity_index) {
+                self.objects[index].end = Some(tick);
+            }
+        }
+
+        match (entity.update_type, active) {
+            (UpdateType::Leave | UpdateType::Delete, _) => {}
+            (_, Some(index)) => self.objects[index].update(entity, tick, state),
+            (_, None) => {
+                let mut object = DemoObject::new(kind, tick);
+                object.update(entity, tick, state);
+                self.active_objects
+                    .insert(entity.entity_index, self.objects.len());
+                self.objects.push(object);
+            }
         }
     }
 
@@ -224,15 +583,14 @@ impl PovAnalyzer {
         data: Option<Stream>,
     ) -> ReadResult<()> {
         if let Some(user_info) = UserInfo::parse_from_string_table(index, text, data)? {
-            if user_info
-                .player_info
-                .name
-                .to_ascii_lowercase()
-                .contains(&self.name)
-            {
-                self.is_pov = user_info.player_info.name == self.pov_name;
+            let name = user_info.player_info.name;
+            if name == self.pov_name {
+                self.pov_player = Some(user_info.entity_id);
+            }
+            if self.player.is_none() && name.to_ascii_lowercase().contains(&self.name) {
                 self.player = Some(user_info.entity_id);
             }
+            self.names.insert(user_info.entity_id, name);
         }
 
         Ok(())
@@ -0,0 +1,348 @@
+use crate::bsp::Map;
+use crate::loader::Loader;
+use crate::material::MaterialSet;
+use crate::Error;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use tracing::{info, warn};
+use vbsp::Bsp;
+use vmdl::mdl::Mdl;
+use vpk::VPK;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Vmt parameters that name a texture
+const TEXTURE_PARAMS: &[&str] = &[
+    "$basetexture",
+    "$basetexture2",
+    "$basetexture3",
+    "$basetexture4",
+    "$bumpmap",
+    "$bumpmap2",
+    "$normalmap",
+    "$normalmap2",
+    "$detail",
+    "$detail2",
+    "$envmap",
+    "$envmapmask",
+    "$envmapmask2",
+    "$blendmodulatetexture",
+    "$phongexponenttexture",
+    "$phongwarptexture",
+    "$lightwarptexture",
+    "$selfillummask",
+    "$selfillumtexture",
+    "$tintmasktexture",
+    "$ambientoccltexture",
+    "$iris",
+    "$corneatexture",
+    "$dudvmap",
+    "$flowmap",
+    "$flow_noise_texture",
+    "$texture2",
+    "$hdrbasetexture",
+    "$hdrcompressedtexture",
+    "$sheenmap",
+    "$sheenmapmask",
+];
+
+/// Vmt parameters that name another material
+const MATERIAL_PARAMS: &[&str] = &["$bottommaterial", "$underwateroverlay", "$crackmaterial"];
+
+/// Index of the files shipped in the tf2 vpks
+pub struct StockContent {
+    vpks: Vec<VPK>,
+}
+
+impl StockContent {
+    /// Open the vpks of the tf2 install the loader uses
+    pub fn new(loader: &Loader) -> Result<Self, Error> {
+        let vpks = loader.stock_vpks()?;
+        if vpks.is_empty() {
+            return Err(Error::Other(
+                "listing custom content needs the tf2 install, it can't be used with --no-tf2"
+                    .into(),
+            ));
+        }
+        Ok(StockContent { vpks })
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.vpks.iter().any(|vpk| vpk.tree.contains_key(path))
+    }
+}
+
+/// Where a custom file used by the map was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// In the map's pakfile
+    Packed,
+    /// Only in the extra asset sources or the install, players won't have it
+    Local,
+    Missing,
+}
+
+pub struct Dependency {
+    pub path: String,
+    pub location: Location,
+}
+
+/// Files referenced by the map's entities that the viewer doesn't load itself, like sounds and sprites
+pub fn entity_files(bsp: &Bsp) -> BTreeSet<String> {
+    // sound names can be prefixed with characters that control how they're played
+    const SOUND_CHARS: &[char] = &[
+        '*', '#', '@', '>', '<', '^', ')', '(', '}', '$', '!', '?', '&', '~', '`', '+', '%',
+    ];
+
+    let mut files = BTreeSet::new();
+    for ent in bsp.entities.iter() {
+        for (_key, value) in ent.properties() {
+            let value = value.replace('\\', "/").to_ascii_lowercase();
+            if value.ends_with(".wav") || value.ends_with(".mp3") {
+                files.insert(format!("sound/{}", value.trim_start_matches(SOUND_CHARS)));
+            } else if value.ends_with(".vmt") {
+                files.insert(material_path(&value));
+            } else if value.ends_with(".mdl") {
+                files.insert(value);
+            }
+        }
+    }
+    files
+}
+
+/// Get the files used by the map that aren't part of the stock content, with where they were found
+///
+/// Besides the files loaded for the viewer this follows every texture of the materials, the other files of the models,
+/// the files referenced by entities and the map's particle, soundscape and sound scripts.
+pub fn custom_files(
+    map: &Map,
+    map_name: &str,
+    loader: &Loader,
+    stock: &StockContent,
+) -> Vec<Dependency> {
+    let mut files: BTreeSet<String> = map.assets.union(&map.entity_files).cloned().collect();
+    for script in [
+        format!("maps/{map_name}_particles.txt"),
+        format!("maps/{map_name}_level_sounds.txt"),
+        format!("scripts/soundscapes_{map_name}.txt"),
+    ] {
+        if loader.exists(&script).unwrap_or_default() {
+            files.insert(script);
+        }
+    }
+
+    let mut queue: Vec<String> = files.iter().cloned().collect();
+    while let Some(path) = queue.pop() {
+        // stock files only refer to other stock files
+        if stock.contains(&path) {
+            continue;
+        }
+        for dependency in file_dependencies(&path, loader) {
+            if files.insert(dependency.clone()) {
+                queue.push(dependency);
+            }
+        }
+    }
+
+    let missing = map.missing.keys().cloned();
+    files
+        .into_iter()
+        .chain(missing)
+        .filter(|path| !stock.contains(path))
+        .map(|path| {
+            let location = if map.packed.contains(&path) {
+                Location::Packed
+            } else if loader.exists(&path).unwrap_or_default() {
+                Location::Local
+            } else {
+                Location::Missing
+            };
+            Dependency { path, location }
+        })
+        .collect()
+}
+
+/// The files a file refers to
+fn file_dependencies(path: &str, loader: &Loader) -> Vec<String> {
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext);
+    let load = || loader.load(path).ok().flatten();
+    match extension {
+        Some("vmt") => {
+            let Some(text) = load().and_then(|data| String::from_utf8(data).ok()) else {
+                return Vec::new();
+            };
+            material_dependencies(&text)
+        }
+        Some("mdl") => {
+            let mut files: Vec<String> = [".vvd", ".dx90.vtx", ".dx80.vtx", ".sw.vtx", ".phy"]
+                .into_iter()
+                .map(|extension| path.replace(".mdl", extension))
+                .filter(|file| loader.exists(file).unwrap_or_default())
+                .collect();
+            // every skin can use different materials, not just the ones loaded for the viewer
+            if let Some(mdl) = load().and_then(|data| Mdl::read(&data).ok()) {
+                let materials = MaterialSet::new(loader);
+                for texture in &mdl.textures {
+                    materials.get_index(texture);
+                }
+                files.extend(materials.into_materials());
+            }
+            files
+        }
+        Some("txt") => {
+            let Some(text) = load().and_then(|data| String::from_utf8(data).ok()) else {
+                return Vec::new();
+            };
+            script_dependencies(&text)
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn material_dependencies(vmt: &str) -> Vec<String> {
+    let mut files = Vec::new();
+    for (key, value) in key_values(vmt) {
+        let key = key.to_ascii_lowercase();
+        let value = value.replace('\\', "/").to_ascii_lowercase();
+        if key == "include" {
+            files.push(value);
+        } else if MATERIAL_PARAMS.contains(&key.as_str()) {
+            files.push(material_path(&value));
+        } else if TEXTURE_PARAMS.contains(&key.as_str())
+            // render targets and cubemaps aren't files
+            && !value.starts_with("_rt_")
+            && value != "env_cubemap"
+        {
+            files.push(format!(
+                "materials/{}.vtf",
+                value.trim_start_matches('/').trim_end_matches(".vtf")
+            ));
+        }
+    }
+    files
+}
+
+/// Particle systems and sounds used by the map's particle manifest and sound scripts
+fn script_dependencies(script: &str) -> Vec<String> {
+    key_values(script)
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = value.replace('\\', "/").to_ascii_lowercase();
+            match key.to_ascii_lowercase().as_str() {
+                // a `!` marks particle files that are loaded before the map
+                "file" => Some(value.trim_start_matches('!').to_string()),
+                "wave" => Some(format!(
+                    "sound/{}",
+                    value.trim_start_matches(|c: char| !c.is_alphanumeric())
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn material_path(name: &str) -> String {
+    if name.starts_with("materials/") {
+        name.into()
+    } else {
+        format!(
+            "materials/{}.vmt",
+            name.trim_start_matches('/').trim_end_matches(".vmt")
+        )
+    }
+}
+
+/// The key value pairs of a keyvalues file like a vmt, including the ones in nested blocks
+fn key_values(text: &str) -> Vec<(&str, &str)> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start();
+        if rest.starts_with("//") {
+            rest = rest.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(quoted) = rest.strip_prefix('"') {
+            let (token, after) = quoted.split_once('"').unwrap_or((quoted, ""));
+            tokens.push(Some(token));
+            rest = after;
+        } else if rest.starts_with('[') {
+            // conditionals like `[$X360]` only apply to the value or block before them
+            rest = rest.split_once(']').map_or("", |(_, rest)| rest);
+        } else if let Some(after) = rest.strip_prefix(['{', '}']) {
+            // blocks only group values, a block name isn't a value
+            tokens.push(None);
+            rest = after;
+        } else if rest.is_empty() {
+            break;
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || matches!(c, '"' | '{' | '}'))
+                .unwrap_or(rest.len());
+            tokens.push(Some(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match (tokens[i], tokens.get(i + 1).copied().flatten()) {
+            (Some(key), Some(value)) => {
+                pairs.push((key, value));
+                i += 2;
+            }
+            _ => i += 1,
+        }
+    }
+    pairs
+}
+
+#[test]
+fn test_material_dependencies() {
+    let vmt = r#"
+        "VertexLitGeneric"
+        {
+            // comment
+            "$basetexture" "custom/wall"
+            $bumpmap custom\wall_normal.vtf
+            "$detail" "custom/detail_console" [$X360]
+            "$envmapmask" "custom/wall_mask"
+            "$envmap" "env_cubemap"
+            "$surfaceprop" "concrete"
+            "Proxies"
+            {
+                "AnimatedTexture" { "animatedtexturevar" "$basetexture" }
+            }
+        }
+    "#;
+    assert_eq!(
+        vec![
+            "materials/custom/wall.vtf",
+            "materials/custom/wall_normal.vtf",
+            "materials/custom/detail_console.vtf",
+            "materials/custom/wall_mask.vtf"
+        ],
+        material_dependencies(vmt)
+    );
+}
+
+/// Write the files into a zip, laid out like the bsp pakfile
+pub fn pack_files(files: &[Dependency], loader: &Loader, path: &Path) -> Result<(), Error> {
+    // pakfiles are stored without compression
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let mut packed = 0;
+    for file in files {
+        let Some(data) = loader.load(&file.path)? else {
+            warn!(file = file.path, "file not found while packing");
+            continue;
+        };
+        zip.start_file(file.path.as_str(), options)?;
+        zip.write_all(&data)?;
+        packed += 1;
+    }
+    zip.finish()?;
+    info!(files = packed, path = ?path, "packed custom content");
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use crate::bsp::UNIT_SCALE;
+use three_d::*;
+use vbsp::{Bsp, Color};
+
+/// Linear distance fog from the map's `env_fog_controller`
+#[derive(Clone, Debug)]
+pub struct Fog {
+    pub color: Srgba,
+    /// Distance where the fog starts, in viewer units
+    pub start: f32,
+    /// Distance where the fog reaches its max density, in viewer units
+    pub end: f32,
+    pub max_density: f32,
+}
+
+pub fn load_fog(bsp: &Bsp) -> Option<Fog> {
+    let ent = bsp
+        .entities
+        .iter()
+        .find(|ent| ent.prop("classname") == Some("env_fog_controller"))?;
+    let enabled: bool = ent
+        .prop_parse("fogenable")
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    if !enabled {
+        return None;
+    }
+
+    let color: Color = ent.prop_parse("fogcolor").and_then(Result::ok)?;
+    let float = |key, default| {
+        ent.prop_parse::<f32>(key)
+            .and_then(Result::ok)
+            .unwrap_or(default)
+    };
+    Some(Fog {
+        color: Srgba::new_opaque(color.r, color.g, color.b),
+        start: float("fogstart", 0.0) * UNIT_SCALE,
+        end: float("fogend", 0.0) * UNIT_SCALE,
+        max_density: float("fogmaxdensity", 1.0),
+    })
+}
+
+/// The fog for the pixels of one depth slice
+pub struct FogSlice<'a> {
+    pub fog: &'a Fog,
+    /// Keep the pixels where nothing was drawn in this slice, they already have the fogged slices behind it
+    pub overlay: bool,
+}
+
+impl Effect for FogSlice<'_> {
+    fn fragment_shader_source(
+        &self,
+        _lights: &[&dyn Light],
+        color_texture: Option<ColorTexture>,
+        depth_texture: Option<DepthTexture>,
+    ) -> String {
+        let color_texture = color_texture.expect("fog needs a color texture");
+        let depth_texture = depth_texture.expect("fog needs a depth texture");
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            color_texture.fragment_shader_source(),
+            depth_texture.fragment_shader_source(),
+            ToneMapping::fragment_shader_source(),
+            ColorMapping::fragment_shader_source(),
+            include_str!("shaders/fog.frag")
+        )
+    }
+
+    fn id(&self, color_texture: Option<ColorTexture>, depth_texture: Option<DepthTexture>) -> u16 {
+        0b1u16 << 8
+            | color_texture.map(|tex| tex.id()).unwrap_or_default()
+            | depth_texture.map(|tex| tex.id()).unwrap_or_default()
+    }
+
+    fn fragment_attributes(&self) -> FragmentAttributes {
+        FragmentAttributes {
+            uv: true,
+            ..FragmentAttributes::NONE
+        }
+    }
+
+    fn use_uniforms(
+        &self,
+        program: &Program,
+        camera: &Camera,
+        _lights: &[&dyn Light],
+        color_texture: Option<ColorTexture>,
+        depth_texture: Option<DepthTexture>,
+    ) {
+        camera.tone_mapping.use_uniforms(program);
+        camera.color_mapping.use_uniforms(program);
+        if let Some(texture) = color_texture {
+            texture.use_uniforms(program);
+        }
+        if let Some(texture) = depth_texture {
+            texture.use_uniforms(program);
+        }
+        program.use_uniform(
+            "viewProjectionInverse",
+            (camera.projection() * camera.view()).invert().unwrap(),
+        );
+        program.use_uniform("fogColor", self.fog.color.to_linear_srgb());
+        program.use_uniform("fogStart", self.fog.start);
+        program.use_uniform("fogEnd", self.fog.end);
+        program.use_uniform("fogMaxDensity", self.fog.max_density);
+        program.use_uniform("overlay", i32::from(self.overlay));
+        program.use_uniform("eyePosition", camera.position());
+    }
+
+    fn render_states(&self) -> RenderStates {
+        RenderStates {
+            depth_test: DepthTest::Always,
+            ..Default::default()
+        }
+    }
+}
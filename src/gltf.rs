@@ -0,0 +1,351 @@
+use crate::Error;
+use image::{DynamicImage, ImageFormat, RgbImage, RgbaImage};
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use three_d::{CpuMaterial, CpuModel, CpuTexture, Mat4, TextureData};
+use three_d_asset::{Geometry, TriMesh};
+use tracing::{info, warn};
+
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const UNSIGNED_BYTE: u32 = 5121;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+/// Write the models as a binary gltf file with embedded textures
+pub fn export_glb(models: &[CpuModel], path: &Path) -> Result<(), Error> {
+    let mut glb = GlbBuilder::default();
+
+    let mut material_offset = 0;
+    let mut model_nodes = Vec::with_capacity(models.len());
+    for model in models {
+        let encoded: Vec<_> = model
+            .materials
+            .par_iter()
+            .map(|material| {
+                (
+                    material.albedo_texture.as_ref().and_then(encode_png),
+                    material.normal_texture.as_ref().and_then(encode_png),
+                )
+            })
+            .collect();
+        for (material, (albedo, normal)) in model.materials.iter().zip(encoded) {
+            glb.push_material(material, albedo, normal);
+        }
+
+        let children: Vec<usize> = model
+            .geometries
+            .iter()
+            .filter_map(|primitive| match &primitive.geometry {
+                Geometry::Triangles(mesh) => Some(glb.push_mesh(
+                    &primitive.name,
+                    mesh,
+                    primitive.material_index.map(|i| i + material_offset),
+                    primitive.transformation,
+                )),
+                _ => None,
+            })
+            .collect();
+        material_offset += model.materials.len();
+
+        model_nodes.push(glb.nodes.len());
+        glb.nodes.push(json!({
+            "name": model.name,
+            "children": children,
+        }));
+    }
+
+    info!(
+        meshes = glb.meshes.len(),
+        materials = glb.materials.len(),
+        textures = glb.textures.len(),
+        "writing glb"
+    );
+    fs::write(path, glb.finish(model_nodes)?)?;
+    Ok(())
+}
+
+pub fn encode_png(texture: &CpuTexture) -> Option<Vec<u8>> {
+    let image = match &texture.data {
+        TextureData::RgbU8(data) => DynamicImage::ImageRgb8(RgbImage::from_raw(
+            texture.width,
+            texture.height,
+            data.iter().flatten().copied().collect(),
+        )?),
+        TextureData::RgbaU8(data) => DynamicImage::ImageRgba8(RgbaImage::from_raw(
+            texture.width,
+            texture.height,
+            data.iter().flatten().copied().collect(),
+        )?),
+        _ => {
+            warn!(
+                texture = texture.name,
+                "unsupported texture format for export"
+            );
+            return None;
+        }
+    };
+    let mut png = Vec::new();
+    match image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png) {
+        Ok(()) => Some(png),
+        Err(e) => {
+            warn!(error = ?e, texture = texture.name, "failed to encode texture");
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+struct GlbBuilder {
+    buffer: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    images: Vec<Value>,
+    textures: Vec<Value>,
+    materials: Vec<Value>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+}
+
+impl GlbBuilder {
+    fn push_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        let offset = self.buffer.len();
+        self.buffer.extend_from_slice(data);
+        while !self.buffer.len().is_multiple_of(4) {
+            self.buffer.push(0);
+        }
+
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": data.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = target.into();
+        }
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    fn push_floats<const N: usize>(
+        &mut self,
+        data: &[[f32; N]],
+        kind: &str,
+        bounds: bool,
+    ) -> usize {
+        let bytes: Vec<u8> = data
+            .iter()
+            .flatten()
+            .flat_map(|float| float.to_le_bytes())
+            .collect();
+        let view = self.push_view(&bytes, Some(ARRAY_BUFFER));
+
+        let mut accessor = json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": data.len(),
+            "type": kind,
+        });
+        if bounds {
+            let mut min = [f32::MAX; N];
+            let mut max = [f32::MIN; N];
+            for item in data {
+                for i in 0..N {
+                    min[i] = min[i].min(item[i]);
+                    max[i] = max[i].max(item[i]);
+                }
+            }
+            accessor["min"] = min.to_vec().into();
+            accessor["max"] = max.to_vec().into();
+        }
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn push_mesh(
+        &mut self,
+        name: &str,
+        mesh: &TriMesh,
+        material: Option<usize>,
+        transformation: Mat4,
+    ) -> usize {
+        let positions: Vec<[f32; 3]> = mesh
+            .positions
+            .to_f32()
+            .into_iter()
+            .map(|position| position.into())
+            .collect();
+        let mut attributes = json!({
+            "POSITION": self.push_floats(&positions, "VEC3", true),
+        });
+        if let Some(normals) = &mesh.normals {
+            let normals: Vec<[f32; 3]> = normals.iter().map(|normal| (*normal).into()).collect();
+            attributes["NORMAL"] = self.push_floats(&normals, "VEC3", false).into();
+        }
+        if let Some(tangents) = &mesh.tangents {
+            let tangents: Vec<[f32; 4]> =
+                tangents.iter().map(|tangent| (*tangent).into()).collect();
+            attributes["TANGENT"] = self.push_floats(&tangents, "VEC4", false).into();
+        }
+        if let Some(uvs) = &mesh.uvs {
+            let uvs: Vec<[f32; 2]> = uvs.iter().map(|uv| (*uv).into()).collect();
+            attributes["TEXCOORD_0"] = self.push_floats(&uvs, "VEC2", false).into();
+        }
+        if let Some(colors) = &mesh.colors {
+            let bytes: Vec<u8> = colors
+                .iter()
+                .flat_map(|color| [color.r, color.g, color.b, color.a])
+                .collect();
+            let view = self.push_view(&bytes, Some(ARRAY_BUFFER));
+            self.accessors.push(json!({
+                "bufferView": view,
+                "componentType": UNSIGNED_BYTE,
+                "normalized": true,
+                "count": colors.len(),
+                "type": "VEC4",
+            }));
+            attributes["COLOR_0"] = (self.accessors.len() - 1).into();
+        }
+
+        let mut primitive = json!({
+            "attributes": attributes,
+        });
+        if let Some(indices) = mesh.indices.to_u32() {
+            let bytes: Vec<u8> = indices
+                .iter()
+                .flat_map(|index| index.to_le_bytes())
+                .collect();
+            let view = self.push_view(&bytes, Some(ELEMENT_ARRAY_BUFFER));
+            self.accessors.push(json!({
+                "bufferView": view,
+                "componentType": UNSIGNED_INT,
+                "count": indices.len(),
+                "type": "SCALAR",
+            }));
+            primitive["indices"] = (self.accessors.len() - 1).into();
+        }
+        if let Some(material) = material {
+            primitive["material"] = material.into();
+        }
+
+        self.meshes.push(json!({
+            "name": name,
+            "primitives": [primitive],
+        }));
+        let matrix: &[f32; 16] = transformation.as_ref();
+        self.nodes.push(json!({
+            "name": name,
+            "mesh": self.meshes.len() - 1,
+            "matrix": matrix.to_vec(),
+        }));
+        self.nodes.len() - 1
+    }
+
+    fn push_texture(&mut self, name: &str, png: &[u8]) -> usize {
+        let view = self.push_view(png, None);
+        self.images.push(json!({
+            "name": name,
+            "bufferView": view,
+            "mimeType": "image/png",
+        }));
+        self.textures.push(json!({
+            "source": self.images.len() - 1,
+        }));
+        self.textures.len() - 1
+    }
+
+    fn push_material(
+        &mut self,
+        material: &CpuMaterial,
+        albedo: Option<Vec<u8>>,
+        normal: Option<Vec<u8>>,
+    ) {
+        let albedo_color = material.albedo.to_linear_srgb();
+        let mut pbr = json!({
+            "baseColorFactor": [albedo_color.x, albedo_color.y, albedo_color.z, albedo_color.w],
+            "metallicFactor": 0.0,
+            "roughnessFactor": 1.0,
+        });
+        let mut transparent = material.albedo.a != 255;
+        if let (Some(png), Some(texture)) = (albedo, &material.albedo_texture) {
+            transparent |= matches!(texture.data, TextureData::RgbaU8(_));
+            pbr["baseColorTexture"] = json!({ "index": self.push_texture(&texture.name, &png) });
+        }
+
+        let mut value = json!({
+            "name": material.name,
+            "pbrMetallicRoughness": pbr,
+        });
+        if let (Some(png), Some(texture)) = (normal, &material.normal_texture) {
+            value["normalTexture"] = json!({ "index": self.push_texture(&texture.name, &png) });
+        }
+        if let Some(cutoff) = material.alpha_cutout {
+            value["alphaMode"] = "MASK".into();
+            value["alphaCutoff"] = cutoff.into();
+        } else if transparent {
+            value["alphaMode"] = "BLEND".into();
+        }
+        self.materials.push(value);
+    }
+
+    fn finish(self, scene_nodes: Vec<usize>) -> Result<Vec<u8>, Error> {
+        let mut root = json!({
+            "asset": {
+                "version": "2.0",
+                "generator": concat!("vbspview ", env!("CARGO_PKG_VERSION")),
+            },
+            "scene": 0,
+            "scenes": [{ "nodes": scene_nodes }],
+        });
+        // gltf doesn't allow empty arrays
+        for (key, items) in [
+            ("nodes", self.nodes),
+            ("meshes", self.meshes),
+            ("materials", self.materials),
+            ("textures", self.textures),
+            ("images", self.images),
+            ("accessors", self.accessors),
+            ("bufferViews", self.buffer_views),
+        ] {
+            if !items.is_empty() {
+                root[key] = items.into();
+            }
+        }
+        if !self.buffer.is_empty() {
+            root["buffers"] = json!([{ "byteLength": self.buffer.len() }]);
+        }
+        let mut json = serde_json::to_vec(&root)?;
+        while !json.len().is_multiple_of(4) {
+            json.push(b' ');
+        }
+
+        let total_length = 12 + 8 + json.len() + 8 + self.buffer.len();
+        let mut glb = Vec::with_capacity(total_length);
+        glb.extend_from_slice(b"glTF");
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&(total_length as u32).to_le_bytes());
+        glb.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"JSON");
+        glb.extend_from_slice(&json);
+        glb.extend_from_slice(&(self.buffer.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"BIN\0");
+        glb.extend_from_slice(&self.buffer);
+        Ok(glb)
+    }
+}
+
+#[test]
+fn test_glb_layout() {
+    let glb = GlbBuilder::default().finish(Vec::new()).unwrap();
+    assert_eq!(b"glTF", &glb[0..4]);
+    assert_eq!(
+        glb.len() as u32,
+        u32::from_le_bytes(glb[8..12].try_into().unwrap())
+    );
+    let json_length = u32::from_le_bytes(glb[12..16].try_into().unwrap()) as usize;
+    assert_eq!(0, json_length % 4);
+    assert_eq!(b"BIN\0", &glb[24 + json_length..28 + json_length]);
+}
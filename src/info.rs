@@ -0,0 +1,80 @@
+use crate::bsp::Map;
+use crate::Error;
+use std::collections::BTreeMap;
+use vbsp::{AsPropPlacement, Bsp};
+use vbsp_entities_tf2::Entity;
+
+/// Counts of the faces and props in a map, for the info report
+#[derive(Default)]
+pub struct MapInfo {
+    /// Number of faces using every texture
+    pub faces: BTreeMap<String, usize>,
+    /// Number of props using every model
+    pub models: BTreeMap<String, usize>,
+    pub static_props: usize,
+    pub dynamic_props: usize,
+}
+
+impl MapInfo {
+    pub fn new(bsp: &Bsp) -> Self {
+        let mut info = MapInfo::default();
+        for model in bsp.models() {
+            for face in model.faces() {
+                *info.faces.entry(face.texture().name().into()).or_default() += 1;
+            }
+        }
+
+        for prop in bsp.static_props() {
+            info.static_props += 1;
+            *info.models.entry(prop.model().into()).or_default() += 1;
+        }
+        for ent in bsp.entities.iter().flat_map(|ent| ent.parse::<Entity>()) {
+            let prop = match ent {
+                Entity::PropDynamic(prop) => prop.as_prop_placement(),
+                Entity::PropPhysics(prop) => prop.as_prop_placement(),
+                Entity::PropDynamicOverride(prop) => prop.as_prop_placement(),
+                _ => continue,
+            };
+            info.dynamic_props += 1;
+            *info.models.entry(prop.model.into()).or_default() += 1;
+        }
+        info
+    }
+}
+
+/// Print the contents of the map and any assets that failed to load
+///
+/// Fails if any asset is missing, so it can be used to check maps in CI
+pub fn print_info(map: &Map) -> Result<(), Error> {
+    let MapInfo {
+        faces,
+        models,
+        static_props,
+        dynamic_props,
+    } = &map.info;
+
+    println!("faces per texture ({} textures):", faces.len());
+    for (texture, count) in faces {
+        println!("\t{count:>6} {texture}");
+    }
+    println!("static props: {static_props}");
+    println!("dynamic props: {dynamic_props}");
+    println!("unique models ({}):", models.len());
+    for (model, count) in models {
+        println!("\t{count:>6} {model}");
+    }
+
+    if map.missing.is_empty() {
+        println!("missing assets: none");
+        Ok(())
+    } else {
+        println!("missing assets ({}):", map.missing.len());
+        for (asset, error) in &map.missing {
+            println!("\t{asset}: {error}");
+        }
+        Err(Error::Other(format!(
+            "{} assets failed to load",
+            map.missing.len()
+        )))
+    }
+}
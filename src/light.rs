@@ -0,0 +1,236 @@
+use crate::bsp::{map_coords, UNIT_SCALE};
+use three_d::{vec3, Attenuation, InnerSpace, Light, Program, Srgba, Vec3};
+use vbsp::{Angles, Bsp, LightColor, RawEntity, Vector};
+
+/// Sun and ambient light from the map's `light_environment`
+pub struct EnvironmentLight {
+    /// Direction the light is shining in
+    pub direction: Vec3,
+    pub color: Srgba,
+    pub intensity: f32,
+    pub ambient_color: Srgba,
+    pub ambient_intensity: f32,
+}
+
+/// A `light` or `light_spot` entity
+pub struct MapLight {
+    pub position: Vec3,
+    pub color: Srgba,
+    pub intensity: f32,
+    /// Attenuation in viewer units
+    pub attenuation: Attenuation,
+    pub spot: Option<SpotCone>,
+}
+
+/// Direction and cone of a `light_spot`
+#[derive(Debug, Clone, Copy)]
+pub struct SpotCone {
+    pub direction: Vec3,
+    /// Angle in degrees where the light starts falling off
+    pub inner: f32,
+    /// Angle in degrees where the light is completely dark
+    pub outer: f32,
+    /// Exponent of the falloff between the inner and outer cone
+    pub exponent: f32,
+}
+
+/// A spot light with Source's falloff between an inner and outer cone
+///
+/// three-d's spot light only has a single cutoff angle with a fixed falloff
+pub struct ConeLight {
+    pub intensity: f32,
+    pub color: Srgba,
+    pub position: Vec3,
+    pub cone: SpotCone,
+    pub attenuation: Attenuation,
+}
+
+impl Light for ConeLight {
+    fn shader_source(&self, i: u32) -> String {
+        format!(
+            "
+            uniform vec3 color{i};
+            uniform vec3 attenuation{i};
+            uniform vec3 position{i};
+            uniform vec3 direction{i};
+            uniform vec3 cone{i};
+            vec3 calculate_lighting{i}(vec3 surface_color, vec3 position, vec3 normal, vec3 view_direction, float metallic, float roughness, float occlusion)
+            {{
+                vec3 light_direction = position{i} - position;
+                float distance = length(light_direction);
+                light_direction = light_direction / distance;
+
+                // cone is the cosine of the inner and outer angle and the exponent
+                float angle = dot(-light_direction, direction{i});
+                if (angle <= cone{i}.y) {{
+                    return vec3(0.0);
+                }}
+                float falloff = 1.0;
+                if (angle < cone{i}.x) {{
+                    falloff = pow((angle - cone{i}.y) / (cone{i}.x - cone{i}.y), cone{i}.z);
+                }}
+                vec3 light_color = attenuate(color{i}, attenuation{i}, distance);
+                return calculate_light(light_color, light_direction, surface_color, view_direction, normal,
+                    metallic, roughness) * falloff;
+            }}
+            "
+        )
+    }
+
+    fn use_uniforms(&self, program: &Program, i: u32) {
+        program.use_uniform(
+            &format!("color{i}"),
+            self.color.to_linear_srgb().truncate() * self.intensity,
+        );
+        program.use_uniform(
+            &format!("attenuation{i}"),
+            vec3(
+                self.attenuation.constant,
+                self.attenuation.linear,
+                self.attenuation.quadratic,
+            ),
+        );
+        program.use_uniform(&format!("position{i}"), self.position);
+        program.use_uniform(&format!("direction{i}"), self.cone.direction.normalize());
+        // the inner cone can't be wider than the outer one
+        let outer = self.cone.outer.to_radians();
+        let inner = self.cone.inner.to_radians().min(outer);
+        program.use_uniform(
+            &format!("cone{i}"),
+            vec3(inner.cos(), outer.cos(), self.cone.exponent),
+        );
+    }
+
+    fn id(&self) -> u8 {
+        // an id not used by the lights from three-d, it's part of the shader cache key
+        0b1u8 << 7 | 0b1000u8
+    }
+}
+
+pub fn load_lights(bsp: &Bsp) -> Vec<MapLight> {
+    bsp.entities
+        .iter()
+        .filter_map(|ent| match ent.prop("classname")? {
+            "light" => load_light(&ent, false),
+            "light_spot" => load_light(&ent, true),
+            _ => None,
+        })
+        .collect()
+}
+
+fn load_light(ent: &RawEntity, spot: bool) -> Option<MapLight> {
+    let origin: Vector = ent.prop_parse("origin").and_then(Result::ok)?;
+    let light: LightColor = ent.prop_parse("_light").and_then(Result::ok)?;
+    let attn = |key| {
+        ent.prop_parse::<f32>(key)
+            .and_then(Result::ok)
+            .unwrap_or_default()
+    };
+    let (constant, linear, mut quadratic) = (
+        attn("_constant_attn"),
+        attn("_linear_attn"),
+        attn("_quadratic_attn"),
+    );
+    if constant == 0.0 && linear == 0.0 && quadratic == 0.0 {
+        quadratic = 1.0;
+    }
+    // the brightness is scaled to be the light's value at 100 units
+    let scale = constant + 100.0 * linear + 10000.0 * quadratic;
+
+    let spot = if spot {
+        let angles: Angles = ent
+            .prop_parse("angles")
+            .and_then(Result::ok)
+            .unwrap_or_default();
+        let pitch = light_pitch(ent, &angles);
+        let float = |key, default| {
+            ent.prop_parse::<f32>(key)
+                .and_then(Result::ok)
+                .unwrap_or(default)
+        };
+        Some(SpotCone {
+            direction: light_direction(pitch, angles.yaw),
+            inner: float("_inner_cone", 30.0),
+            outer: float("_cone", 45.0),
+            exponent: float("_exponent", 1.0),
+        })
+    } else {
+        None
+    };
+
+    let (color, intensity) = light_color(&light);
+    Some(MapLight {
+        position: map_coords(origin),
+        color,
+        intensity: intensity * scale,
+        attenuation: Attenuation {
+            constant,
+            linear: linear / UNIT_SCALE,
+            quadratic: quadratic / (UNIT_SCALE * UNIT_SCALE),
+        },
+        spot,
+    })
+}
+
+// the typed entity can't parse `pitch` since it's a single angle, so we read the raw properties
+pub fn load_environment_light(bsp: &Bsp) -> Option<EnvironmentLight> {
+    let ent = bsp
+        .entities
+        .iter()
+        .find(|ent| ent.prop("classname") == Some("light_environment"))?;
+
+    let angles: Angles = ent.prop_parse("angles").and_then(Result::ok)?;
+    let pitch = light_pitch(&ent, &angles);
+    let light: LightColor = ent.prop_parse("_light").and_then(Result::ok)?;
+    let ambient: Option<LightColor> = ent.prop_parse("_ambient").and_then(Result::ok);
+
+    let (color, intensity) = light_color(&light);
+    let (ambient_color, ambient_intensity) = ambient
+        .as_ref()
+        .map(light_color)
+        .unwrap_or((Srgba::WHITE, 0.0));
+    Some(EnvironmentLight {
+        direction: light_direction(pitch, angles.yaw),
+        color,
+        intensity,
+        ambient_color,
+        ambient_intensity,
+    })
+}
+
+/// Pitch of a light like vrad's `SetupLightNormalFromProps`, the angles are only used when the `pitch` key is missing or 0
+fn light_pitch(ent: &RawEntity, angles: &Angles) -> f32 {
+    ent.prop_parse::<f32>("pitch")
+        .and_then(Result::ok)
+        .filter(|pitch| *pitch != 0.0)
+        .unwrap_or(angles.pitch)
+}
+
+/// Direction in viewer coordinates for a light pitch and yaw in degrees
+fn light_direction(pitch: f32, yaw: f32) -> Vec3 {
+    let (pitch, yaw) = (pitch.to_radians(), yaw.to_radians());
+    map_coords(Vector {
+        x: yaw.cos() * pitch.cos(),
+        y: yaw.sin() * pitch.cos(),
+        z: pitch.sin(),
+    })
+    .normalize()
+}
+
+/// Color and intensity from a light value, the brightness is relative to 255
+fn light_color(light: &LightColor) -> (Srgba, f32) {
+    (
+        Srgba::new_opaque(light.r, light.g, light.b),
+        light.intensity as f32 / 255.0,
+    )
+}
+
+#[test]
+fn test_light_direction() {
+    let down = light_direction(-90.0, 0.0);
+    assert!((down - Vec3::new(0.0, -1.0, 0.0)).magnitude() < 0.001);
+
+    // yaw 0 points along the source x axis, which is the viewer z axis
+    let forward = light_direction(0.0, 0.0);
+    assert!((forward - Vec3::new(0.0, 0.0, 1.0)).magnitude() < 0.001);
+}
@@ -0,0 +1,393 @@
+use crate::bsp::lump_data;
+use std::collections::HashMap;
+use std::sync::Arc;
+use three_d::*;
+use tracing::warn;
+use vbsp::{Bsp, Face, Handle, Vector};
+
+const LIGHTING_LUMP: usize = 8;
+const LIGHTING_HDR_LUMP: usize = 53;
+
+/// Size of the white block at the start of the atlas, used for faces without lightmap
+const UNLIT_SIZE: u32 = 2;
+
+/// Baked lighting for the world model
+pub struct Lightmap {
+    pub atlas: CpuTexture,
+    /// Lightmap coordinates for every vertex, for each primitive of the world model
+    pub uvs: Vec<Vec<Vec2>>,
+}
+
+/// Placement of all face lightmaps inside a single atlas texture
+pub struct LightmapAtlas<'a> {
+    samples: &'a [u8],
+    width: u32,
+    height: u32,
+    /// Atlas position and size of each face lightmap, by the offset in the lighting lump
+    rects: HashMap<i32, ([u32; 2], [u32; 2])>,
+}
+
+impl<'a> LightmapAtlas<'a> {
+    pub fn new(data: &'a [u8], bsp: &Bsp) -> Option<Self> {
+        let samples = lump_data(data, LIGHTING_LUMP)
+            .or_else(|| lump_data(data, LIGHTING_HDR_LUMP))?
+            .1;
+
+        let mut faces: Vec<_> = bsp
+            .faces
+            .iter()
+            .filter(|face| face.light_offset >= 0 && face.styles[0] != 255)
+            .map(|face| {
+                (
+                    face.light_offset,
+                    [
+                        face.light_map_texture_size[0] as u32 + 1,
+                        face.light_map_texture_size[1] as u32 + 1,
+                    ],
+                )
+            })
+            .collect();
+        faces.sort_by_key(|(offset, [_, height])| (u32::MAX - height, *offset));
+        faces.dedup_by_key(|(offset, _)| *offset);
+
+        let area: u32 = faces.iter().map(|(_, [w, h])| w * h).sum();
+        let widest = faces.iter().map(|(_, [w, _])| *w).max().unwrap_or_default();
+        let width = ((area as f32).sqrt() as u32)
+            .max(widest)
+            .next_power_of_two();
+
+        // simple shelf packing, the unlit block takes the start of the first shelf
+        let mut rects = HashMap::with_capacity(faces.len());
+        let (mut x, mut y, mut shelf_height) = (UNLIT_SIZE, 0, UNLIT_SIZE);
+        for (offset, size) in faces {
+            if x + size[0] > width {
+                x = 0;
+                y += shelf_height;
+                shelf_height = 0;
+            }
+            rects.insert(offset, ([x, y], size));
+            x += size[0];
+            shelf_height = shelf_height.max(size[1]);
+        }
+        let height = (y + shelf_height).next_power_of_two();
+
+        Some(LightmapAtlas {
+            samples,
+            width,
+            height,
+            rects,
+        })
+    }
+
+    /// Get the lightmap coordinates for a position on a face
+    pub fn uv(&self, face: &Handle<Face>, position: Vector) -> Vec2 {
+        let Some(([x, y], _)) = self.rects.get(&face.light_offset) else {
+            return vec2(
+                UNLIT_SIZE as f32 / 2.0 / self.width as f32,
+                UNLIT_SIZE as f32 / 2.0 / self.height as f32,
+            );
+        };
+        let texture = face.texture();
+        let project = |axis: [f32; 4]| {
+            axis[0] * position.x + axis[1] * position.y + axis[2] * position.z + axis[3]
+        };
+        let s = project(texture.light_map_scale) - face.light_map_texture_min[0] as f32;
+        let t = project(texture.light_map_transform) - face.light_map_texture_min[1] as f32;
+
+        vec2(
+            (*x as f32 + s + 0.5) / self.width as f32,
+            (*y as f32 + t + 0.5) / self.height as f32,
+        )
+    }
+
+    /// Decode the lightmap samples into the atlas texture
+    pub fn into_texture(self) -> CpuTexture {
+        let mut pixels = vec![[0.0; 3]; (self.width * self.height) as usize];
+        for y in 0..UNLIT_SIZE {
+            for x in 0..UNLIT_SIZE {
+                pixels[(y * self.width + x) as usize] = [1.0; 3];
+            }
+        }
+
+        for (offset, ([x, y], [width, height])) in self.rects {
+            let Some(samples) = self
+                .samples
+                .get(offset as usize..offset as usize + (width * height * 4) as usize)
+            else {
+                warn!(offset, "lightmap samples out of bounds");
+                continue;
+            };
+            for (i, sample) in samples.chunks_exact(4).enumerate() {
+                let i = i as u32;
+                let pixel = (y + i / width) * self.width + x + i % width;
+                pixels[pixel as usize] = decode_sample(sample);
+            }
+        }
+
+        CpuTexture {
+            name: "lightmap".into(),
+            data: TextureData::RgbF32(pixels),
+            width: self.width,
+            height: self.height,
+            wrap_s: Wrapping::ClampToEdge,
+            wrap_t: Wrapping::ClampToEdge,
+            mip_map_filter: None,
+            ..CpuTexture::default()
+        }
+    }
+}
+
+/// Decode a `ColorRGBExp32` sample into linear color
+fn decode_sample(sample: &[u8]) -> [f32; 3] {
+    let exponent = 2f32.powi(sample[3] as i8 as i32);
+    [
+        sample[0] as f32 / 255.0 * exponent,
+        sample[1] as f32 / 255.0 * exponent,
+        sample[2] as f32 / 255.0 * exponent,
+    ]
+}
+
+#[test]
+fn test_decode_sample() {
+    assert_eq!([1.0, 0.0, 1.0], decode_sample(&[255, 0, 255, 0]));
+    assert_eq!([0.5, 0.0, 0.0], decode_sample(&[255, 0, 0, 255]));
+    assert_eq!([2.0, 0.0, 0.0], decode_sample(&[255, 0, 0, 1]));
+}
+
+/// A triangle mesh with a second set of uv coordinates for the lightmap
+pub struct LightmappedMesh {
+    context: Context,
+    positions: VertexBuffer,
+    normals: Option<VertexBuffer>,
+    uvs: VertexBuffer,
+    lightmap_uvs: VertexBuffer,
+    colors: Option<VertexBuffer>,
+    indices: Option<ElementBuffer>,
+    aabb: AxisAlignedBoundingBox,
+    transformation: Mat4,
+}
+
+impl LightmappedMesh {
+    pub fn new(
+        context: &Context,
+        mesh: &CpuMesh,
+        lightmap_uvs: &[Vec2],
+        transformation: Mat4,
+    ) -> Self {
+        let mut aabb = mesh.compute_aabb();
+        aabb.transform(&transformation);
+        let uvs: Vec<Vec2> = match &mesh.uvs {
+            Some(uvs) => uvs.iter().map(|uv| vec2(uv.x, 1.0 - uv.y)).collect(),
+            None => vec![Vec2::zero(); lightmap_uvs.len()],
+        };
+
+        LightmappedMesh {
+            context: context.clone(),
+            positions: VertexBuffer::new_with_data(context, &mesh.positions.to_f32()),
+            normals: mesh
+                .normals
+                .as_ref()
+                .map(|normals| VertexBuffer::new_with_data(context, normals)),
+            uvs: VertexBuffer::new_with_data(context, &uvs),
+            lightmap_uvs: VertexBuffer::new_with_data(context, lightmap_uvs),
+            colors: mesh.colors.as_ref().map(|colors| {
+                VertexBuffer::new_with_data(
+                    context,
+                    &colors
+                        .iter()
+                        .map(|color| color.to_linear_srgb())
+                        .collect::<Vec<_>>(),
+                )
+            }),
+            indices: match &mesh.indices {
+                Indices::U8(indices) => Some(ElementBuffer::new_with_data(context, indices)),
+                Indices::U16(indices) => Some(ElementBuffer::new_with_data(context, indices)),
+                Indices::U32(indices) => Some(ElementBuffer::new_with_data(context, indices)),
+                Indices::None => None,
+            },
+            aabb,
+            transformation,
+        }
+    }
+}
+
+impl Geometry for LightmappedMesh {
+    fn draw(
+        &self,
+        camera: &Camera,
+        program: &Program,
+        render_states: RenderStates,
+        attributes: FragmentAttributes,
+    ) {
+        program.use_uniform("viewProjection", camera.projection() * camera.view());
+        program.use_uniform("modelMatrix", self.transformation);
+        program.use_vertex_attribute("position", &self.positions);
+        program.use_vertex_attribute("lightmap_coordinates", &self.lightmap_uvs);
+        if attributes.uv {
+            program.use_vertex_attribute("uv_coordinates", &self.uvs);
+        }
+        if attributes.normal {
+            if let (Some(normals), Some(inverse)) = (&self.normals, self.transformation.invert()) {
+                program.use_uniform("normalMatrix", inverse.transpose());
+                program.use_vertex_attribute("normal", normals);
+            }
+        }
+        if attributes.color {
+            if let Some(colors) = &self.colors {
+                program.use_vertex_attribute("color", colors);
+            }
+        }
+
+        if let Some(indices) = &self.indices {
+            program.draw_elements(render_states, camera.viewport(), indices)
+        } else {
+            program.draw_arrays(
+                render_states,
+                camera.viewport(),
+                self.positions.vertex_count(),
+            )
+        }
+    }
+
+    fn vertex_shader_source(&self, required_attributes: FragmentAttributes) -> String {
+        format!(
+            "{}{}{}{}",
+            if required_attributes.normal && self.normals.is_some() {
+                "#define USE_NORMALS\n"
+            } else {
+                ""
+            },
+            if required_attributes.uv {
+                "#define USE_UVS\n"
+            } else {
+                ""
+            },
+            if required_attributes.color && self.colors.is_some() {
+                "#define USE_VERTEX_COLORS\n"
+            } else {
+                ""
+            },
+            include_str!("shaders/lightmapped.vert"),
+        )
+    }
+
+    fn id(&self, required_attributes: FragmentAttributes) -> u16 {
+        let mut id = 0b1u16 << 8;
+        if required_attributes.normal && self.normals.is_some() {
+            id |= 0b1u16;
+        }
+        if required_attributes.uv {
+            id |= 0b1u16 << 1;
+        }
+        if required_attributes.color && self.colors.is_some() {
+            id |= 0b1u16 << 2;
+        }
+        id
+    }
+
+    fn render_with_material(
+        &self,
+        material: &dyn Material,
+        camera: &Camera,
+        lights: &[&dyn Light],
+    ) {
+        render_with_material(&self.context, camera, self, material, lights)
+    }
+
+    fn render_with_effect(
+        &self,
+        material: &dyn Effect,
+        camera: &Camera,
+        lights: &[&dyn Light],
+        color_texture: Option<ColorTexture>,
+        depth_texture: Option<DepthTexture>,
+    ) {
+        render_with_effect(
+            &self.context,
+            camera,
+            self,
+            material,
+            lights,
+            color_texture,
+            depth_texture,
+        )
+    }
+
+    fn aabb(&self) -> AxisAlignedBoundingBox {
+        self.aabb
+    }
+}
+
+/// Unlit material that multiplies the surface color with the baked lighting
+#[derive(Clone)]
+pub struct LightmapMaterial {
+    pub albedo: Srgba,
+    pub albedo_texture: Option<Texture2DRef>,
+    pub lightmap: Arc<Texture2D>,
+    pub render_states: RenderStates,
+    pub is_transparent: bool,
+}
+
+impl LightmapMaterial {
+    pub fn from_physical_material(material: &PhysicalMaterial, lightmap: Arc<Texture2D>) -> Self {
+        LightmapMaterial {
+            albedo: material.albedo,
+            albedo_texture: material.albedo_texture.clone(),
+            lightmap,
+            render_states: material.render_states,
+            is_transparent: material.is_transparent,
+        }
+    }
+}
+
+impl Material for LightmapMaterial {
+    fn fragment_shader_source(&self, _lights: &[&dyn Light]) -> String {
+        let mut shader = String::new();
+        if self.albedo_texture.is_some() {
+            shader.push_str("#define USE_TEXTURE\nin vec2 uvs;\n");
+        }
+        shader.push_str(ToneMapping::fragment_shader_source());
+        shader.push_str(ColorMapping::fragment_shader_source());
+        shader.push_str(include_str!("shaders/lightmapped.frag"));
+        shader
+    }
+
+    fn id(&self) -> u16 {
+        if self.albedo_texture.is_some() {
+            0b1u16 << 8
+        } else {
+            0b1u16 << 8 | 0b1u16
+        }
+    }
+
+    fn fragment_attributes(&self) -> FragmentAttributes {
+        FragmentAttributes {
+            color: true,
+            uv: self.albedo_texture.is_some(),
+            ..FragmentAttributes::NONE
+        }
+    }
+
+    fn use_uniforms(&self, program: &Program, camera: &Camera, _lights: &[&dyn Light]) {
+        camera.tone_mapping.use_uniforms(program);
+        camera.color_mapping.use_uniforms(program);
+        program.use_uniform("surfaceColor", self.albedo.to_linear_srgb());
+        program.use_texture("lightmap", &self.lightmap);
+        if let Some(texture) = &self.albedo_texture {
+            program.use_uniform("textureTransformation", texture.transformation);
+            program.use_texture("tex", texture);
+        }
+    }
+
+    fn render_states(&self) -> RenderStates {
+        self.render_states
+    }
+
+    fn material_type(&self) -> MaterialType {
+        if self.is_transparent {
+            MaterialType::Transparent
+        } else {
+            MaterialType::Opaque
+        }
+    }
+}
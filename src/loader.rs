@@ -0,0 +1,133 @@
+use crate::Error;
+use std::env::var_os;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use steamlocate::SteamDir;
+use tf_asset_loader::{AssetSource, LoaderError};
+use tracing::warn;
+use vpk::VPK;
+
+/// The loader from `tf_asset_loader` with the tf2 install made optional
+///
+/// Sources added before the install are searched before it, so they can override the stock files.
+/// Sources added after it are searched after the install, like with the upstream loader.
+#[derive(Clone, Default)]
+pub struct Loader {
+    overrides: Vec<Arc<dyn AssetSource + Send + Sync>>,
+    install: Option<Install>,
+}
+
+#[derive(Clone)]
+struct Install {
+    dir: PathBuf,
+    loader: tf_asset_loader::Loader,
+}
+
+impl Debug for Loader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Loader")
+            .field("overrides", &self.overrides.len())
+            .field(
+                "install",
+                &self.install.as_ref().map(|install| &install.dir),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Loader {
+    /// Add the tf2 install, from `TF_DIR` or the steam library
+    pub fn add_tf2_install(&mut self) -> Result<(), Error> {
+        let dir = tf2_path().ok_or(LoaderError::Tf2NotFound)?;
+        let loader = tf_asset_loader::Loader::with_tf2_dir(&dir)?;
+        self.install = Some(Install { dir, loader });
+        Ok(())
+    }
+
+    pub fn add_source<S: AssetSource + Send + Sync + 'static>(&mut self, source: S) {
+        match self.install.as_mut() {
+            Some(install) => install.loader.add_source(source),
+            None => self.overrides.push(Arc::new(source)),
+        }
+    }
+
+    /// Open the vpks of the tf2 install, empty if the install isn't used
+    pub fn stock_vpks(&self) -> Result<Vec<VPK>, Error> {
+        let Some(install) = &self.install else {
+            return Ok(Vec::new());
+        };
+        let mut vpks = Vec::new();
+        for dir in ["tf", "hl2"] {
+            for entry in install.dir.join(dir).read_dir()? {
+                let path = entry?.path();
+                if !path.to_string_lossy().ends_with("dir.vpk") {
+                    continue;
+                }
+                match vpk::from_path(&path) {
+                    Ok(vpk) => vpks.push(vpk),
+                    Err(e) => warn!(error = ?e, vpk = ?path, "error while loading vpk"),
+                }
+            }
+        }
+        Ok(vpks)
+    }
+
+    pub fn exists(&self, name: &str) -> Result<bool, LoaderError> {
+        for name in with_lowercase(name) {
+            for source in &self.overrides {
+                if source.has(&name)? {
+                    return Ok(true);
+                }
+            }
+        }
+        match &self.install {
+            Some(install) => install.loader.exists(name),
+            None => Ok(false),
+        }
+    }
+
+    /// Load a file by path, `None` if no source has it
+    pub fn load(&self, name: &str) -> Result<Option<Vec<u8>>, LoaderError> {
+        for name in with_lowercase(name) {
+            for source in &self.overrides {
+                if let Some(data) = source.load(&name)? {
+                    return Ok(Some(data));
+                }
+            }
+        }
+        match &self.install {
+            Some(install) => install.loader.load(name),
+            None => Ok(None),
+        }
+    }
+
+    /// Look for a file by name in one or more directories
+    pub fn find_in_paths<S: Display>(&self, name: &str, paths: &[S]) -> Option<String> {
+        let overridden = paths.iter().find_map(|path| {
+            let full_path = format!("{path}{name}");
+            let found = with_lowercase(&full_path).any(|name| {
+                self.overrides
+                    .iter()
+                    .any(|source| source.has(&name).unwrap_or_default())
+            });
+            found.then_some(full_path)
+        });
+        overridden.or_else(|| self.install.as_ref()?.loader.find_in_paths(name, paths))
+    }
+}
+
+fn tf2_path() -> Option<PathBuf> {
+    if let Some(path) = var_os("TF_DIR") {
+        return Some(path.into());
+    }
+    let (app, library) = SteamDir::locate().ok()?.find_app(440).ok()??;
+    Some(library.resolve_app_dir(&app))
+}
+
+/// The name, followed by its lowercase version if that is different
+fn with_lowercase(name: &str) -> impl Iterator<Item = String> {
+    let lower = name.to_ascii_lowercase();
+    let lower = (lower != name).then_some(lower);
+    std::iter::once(name.to_string()).chain(lower)
+}
@@ -1,34 +1,61 @@
+mod animation;
 mod bsp;
 mod control;
 mod demo;
+mod deps;
+mod fog;
+mod gltf;
+mod info;
+mod light;
+mod lightmap;
+mod loader;
 mod material;
+mod obj;
+mod player;
+mod projectile;
 mod prop;
 mod renderer;
+mod sky;
 mod ui;
+mod viewer;
+mod vis;
 mod wrapping;
 
 use clap::Parser;
-use std::fs;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::string::FromUtf8Error;
-use tf_asset_loader::{Loader, LoaderError};
+use std::sync::{Arc, Mutex};
+use tf_asset_loader::LoaderError;
 
-use crate::bsp::load_map;
+use crate::bsp::{load_map, Map};
 use crate::control::{Control, DemoCamera};
-use crate::demo::DemoInfo;
+use crate::demo::{DemoEntities, DemoInfo};
+use crate::deps::{custom_files, pack_files, Location, StockContent};
+use crate::gltf::export_glb;
+use crate::info::print_info;
+use crate::lightmap::{LightmapMaterial, LightmappedMesh};
+use crate::loader::Loader;
+use crate::obj::export_obj;
+use crate::player::DemoPlayers;
+use crate::projectile::DemoObjects;
+use crate::prop::InstancedProp;
 use crate::renderer::Renderer;
 use crate::ui::DebugUI;
+use crate::viewer::view_model;
 use control::FirstPerson;
 use thiserror::Error;
 use three_d::*;
 use tracing_subscriber::{prelude::*, EnvFilter};
 use tracing_tree::HierarchicalLayer;
 use vmt_parser::VdfError;
+use zip::ZipArchive;
 
 /// View a demo file
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Path of the demo or map file
+    /// Path of the demo, map or model file, models can also be given by their name in the game files
     path: String,
     /// Name of the player to follow, when using a demo file
     player: Option<String>,
@@ -38,6 +65,29 @@ struct Args {
     /// Disable loading of textures
     #[arg(long)]
     no_textures: bool,
+    /// Extra directory, vpk or zip file to load assets from, can be used multiple times
+    #[arg(long = "assets", value_name = "PATH")]
+    asset_sources: Vec<PathBuf>,
+    /// Don't look for a local tf2 install, only load assets from the map and the extra asset sources
+    #[arg(long)]
+    no_tf2: bool,
+    /// Export the map to a binary gltf (.glb) or wavefront (.obj) file instead of opening the viewer
+    #[arg(long, value_name = "FILE")]
+    export: Option<PathBuf>,
+    /// Print the map contents and missing assets instead of opening the viewer, fails if any asset is missing
+    ///
+    /// Can't be combined with `--no-props` or `--no-textures`, the assets they skip would never be checked
+    #[arg(long, conflicts_with_all = ["no_props", "no_textures"])]
+    info: bool,
+    /// List the files the map uses that aren't part of the stock tf2 content, marked as packed in the map, local only or missing
+    #[arg(long)]
+    dependencies: bool,
+    /// Write the files the map uses that aren't part of the stock tf2 content into a zip, laid out like the bsp pakfile
+    #[arg(long, value_name = "FILE")]
+    pack: Option<PathBuf>,
+    /// Keep hammer units in exported obj files instead of scaling to viewer units
+    #[arg(long)]
+    hammer_units: bool,
 }
 
 #[derive(Debug, Error)]
@@ -66,6 +116,12 @@ pub enum Error {
     String(#[from] FromUtf8Error),
     #[error(transparent)]
     Loader(#[from] LoaderError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Vpk(#[from] vpk::Error),
     #[error("resource {0} not found in vpks or pack")]
     ResourceNotFound(String),
 }
@@ -89,46 +145,219 @@ fn setup() {
         .init();
 }
 
+fn create_loader(no_tf2: bool, sources: &[PathBuf]) -> Result<Loader, Error> {
+    // the extra sources come first so they can override stock files
+    let mut loader = Loader::default();
+    for source in sources {
+        let extension = source.extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some("vpk") => loader.add_source(vpk::from_path(source)?),
+            Some("zip") => loader.add_source(Mutex::new(ZipArchive::new(File::open(source)?)?)),
+            _ if source.is_dir() => loader.add_source(source.clone()),
+            _ => {
+                return Err(Error::Other(format!(
+                    "{} is not a directory, vpk or zip file",
+                    source.display()
+                )))
+            }
+        }
+    }
+    if !no_tf2 {
+        loader.add_tf2_install()?;
+    }
+    Ok(loader)
+}
+
+/// Name of a map from the path of its bsp
+fn map_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
 fn main() -> Result<(), Error> {
     setup();
 
     let args = Args::parse();
 
-    let window = Window::new(WindowSettings {
-        title: args.path.clone(),
-        max_size: Some((1920, 1080)),
-        ..Default::default()
-    })?;
+    let mut loader = create_loader(args.no_tf2, &args.asset_sources)?;
+    if args.path.ends_with(".mdl") {
+        return view_model(create_window(&args.path)?, loader, &args.path);
+    }
 
-    if args.path.ends_with(".dem") {
-        let demo = DemoInfo::new(args.path, &args.player.unwrap_or_default())?;
-        let mut loader = Loader::new()?;
+    let (data, demo) = if args.path.ends_with(".dem") {
+        let demo = DemoInfo::new(&args.path, &args.player.unwrap_or_default())?;
         let map = loader
             .load(&format!("maps/{}.bsp", demo.map))?
             .ok_or(Error::ResourceNotFound(demo.map.clone()))?;
-
-        let models = load_map(&map, &mut loader, !args.no_props, !args.no_textures)?;
-        play(window, DemoCamera::new(demo), models)
+        (map, Some(demo))
     } else {
-        let mut loader = Loader::new()?;
-        let map = fs::read(args.path)?;
+        (fs::read(&args.path)?, None)
+    };
 
-        let models = load_map(&map, &mut loader, !args.no_props, !args.no_textures)?;
-        play(window, FirstPerson::new(0.1), models)
+    let map = load_map(&data, &mut loader, !args.no_props, !args.no_textures)?;
+
+    if args.info {
+        return print_info(&map);
+    }
+
+    if args.dependencies || args.pack.is_some() {
+        let stock = StockContent::new(&loader)?;
+        let map_name = match &demo {
+            Some(demo) => demo.map.clone(),
+            None => map_name(&args.path),
+        };
+        let files = custom_files(&map, &map_name, &loader, &stock);
+        for file in &files {
+            let location = match file.location {
+                Location::Packed => "packed",
+                Location::Local => "local only",
+                Location::Missing => "missing",
+            };
+            println!("{} ({location})", file.path);
+        }
+        if let Some(pack) = &args.pack {
+            let present: Vec<_> = files
+                .into_iter()
+                .filter(|file| file.location != Location::Missing)
+                .collect();
+            pack_files(&present, &loader, pack)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(export) = args.export {
+        let mut models = map.models;
+        if !map.props.models.is_empty() {
+            models.push(map.props.to_model());
+        }
+        return match export.extension().and_then(|ext| ext.to_str()) {
+            Some("obj") => export_obj(&models, &export, args.hammer_units),
+            _ => export_glb(&models, &export),
+        };
+    }
+
+    let window = create_window(&args.path)?;
+    match demo {
+        Some(mut demo) => {
+            // the camera keeps the players to switch between them
+            let entities = DemoEntities {
+                players: demo.entities.players.clone(),
+                objects: std::mem::take(&mut demo.entities.objects),
+            };
+            play(window, DemoCamera::new(demo), map, &loader, entities)
+        }
+        None => play(
+            window,
+            FirstPerson::new(0.1),
+            map,
+            &loader,
+            DemoEntities::default(),
+        ),
     }
 }
 
+fn create_window(title: &str) -> Result<Window, Error> {
+    Ok(Window::new(WindowSettings {
+        title: title.into(),
+        max_size: Some((1920, 1080)),
+        ..Default::default()
+    })?)
+}
+
 fn play<C: Control + 'static>(
     window: Window,
     control: C,
-    models: Vec<CpuModel>,
+    map: Map,
+    loader: &Loader,
+    entities: DemoEntities,
 ) -> Result<(), Error> {
     let mut renderer = Renderer::new(&window, control);
+    renderer.set_bounds(map.bounds);
 
-    renderer.models = models
-        .into_iter()
-        .map(|model| Model::new(&renderer.context, &model))
+    renderer.models = map
+        .models
+        .iter()
+        .map(|model| Model::new(&renderer.context, model))
         .collect::<Result<_, _>>()?;
+    let prop_materials: Vec<_> = map
+        .props
+        .materials
+        .iter()
+        .map(|material| PhysicalMaterial::new(&renderer.context, material))
+        .collect();
+    let sky_clusters = map
+        .sky
+        .camera
+        .as_ref()
+        .map(|camera| camera.visible_clusters.as_slice());
+    renderer.props = map
+        .props
+        .models
+        .iter()
+        .map(|model| InstancedProp::new(&renderer.context, model, &prop_materials, sky_clusters))
+        .collect();
+    renderer.prop_screen_fade = map.props.screen_fade;
+    if !entities.players.is_empty() {
+        renderer.players = Some(DemoPlayers::new(
+            &renderer.context,
+            loader,
+            entities.players,
+        ));
+    }
+    if !entities.objects.is_empty() {
+        renderer.demo_objects = Some(DemoObjects::new(
+            &renderer.context,
+            loader,
+            entities.objects,
+        ));
+    }
+
+    if let (Some(lightmap), Some(world), Some(cpu_world)) =
+        (map.lightmap, renderer.models.first(), map.models.first())
+    {
+        let atlas = Arc::new(Texture2D::new(&renderer.context, &lightmap.atlas));
+        renderer.lightmapped_world = cpu_world
+            .geometries
+            .iter()
+            .zip(lightmap.uvs.iter())
+            .zip(world.iter())
+            .filter_map(|((primitive, uvs), gm)| match &primitive.geometry {
+                three_d_asset::Geometry::Triangles(mesh) => Some(Gm {
+                    geometry: LightmappedMesh::new(
+                        &renderer.context,
+                        mesh,
+                        uvs,
+                        primitive.transformation,
+                    ),
+                    material: LightmapMaterial::from_physical_material(&gm.material, atlas.clone()),
+                }),
+                _ => None,
+            })
+            .collect();
+    }
+    renderer.visibility = map.visibility;
+    renderer.skybox = map
+        .sky
+        .faces
+        .map(|[right, left, top, bottom, front, back]| {
+            Skybox::new(
+                &renderer.context,
+                &right,
+                &left,
+                &top,
+                &bottom,
+                &front,
+                &back,
+            )
+        });
+    renderer.sky_camera = map.sky.camera;
+    if let Some(light) = map.environment_light {
+        renderer.set_environment_light(light);
+    }
+    renderer.set_map_lights(&map.lights);
+    renderer.fog = map.fog;
 
     window.render_loop(move |frame_input| renderer.render(frame_input));
 
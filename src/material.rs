@@ -1,7 +1,9 @@
+use crate::loader::Loader;
 use crate::Error;
 use image::DynamicImage;
 use std::cell::RefCell;
-use tf_asset_loader::Loader;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
 use three_d::{CpuMaterial, CpuTexture};
 use three_d_asset::Srgba;
 use tracing::{error, instrument};
@@ -10,11 +12,12 @@ use vmt_parser::material::{Material, WaterMaterial};
 use vmt_parser::{from_str, TextureTransform};
 use vtf::vtf::VTF;
 
-pub fn load_material_fallback(name: &str, loader: &Loader) -> MaterialData {
-    match load_material(name, loader) {
+pub fn load_material_fallback(name: &str, loader: &Loader, assets: &AssetLog) -> MaterialData {
+    match load_material(name, loader, assets) {
         Ok(mat) => mat,
         Err(e) => {
             error!(error = ?e, material = name, "failed to load material");
+            assets.missing(name, &e);
             MaterialData {
                 path: name.into(),
                 color: [255, 0, 255, 255],
@@ -24,6 +27,47 @@ pub fn load_material_fallback(name: &str, loader: &Loader) -> MaterialData {
     }
 }
 
+/// Files loaded while loading a map, and the assets that couldn't be loaded with the reason
+#[derive(Default, Debug)]
+pub struct AssetLog {
+    loaded: Mutex<BTreeSet<String>>,
+    missing: Mutex<BTreeMap<String, String>>,
+}
+
+impl AssetLog {
+    /// Load a file from the loader, recording it as used
+    pub fn load(&self, loader: &Loader, path: &str) -> Result<Vec<u8>, Error> {
+        let data = loader
+            .load(path)?
+            .ok_or_else(|| Error::ResourceNotFound(path.into()))?;
+        self.loaded
+            .lock()
+            .unwrap()
+            .insert(path.to_ascii_lowercase());
+        Ok(data)
+    }
+
+    /// Record a failed asset, for missing resources the missing file is recorded instead of `name`
+    pub fn missing(&self, name: &str, error: &Error) {
+        let name = match error {
+            Error::ResourceNotFound(path) => path,
+            _ => name,
+        };
+        self.missing
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| error.to_string());
+    }
+
+    pub fn into_parts(self) -> (BTreeSet<String>, BTreeMap<String, String>) {
+        (
+            self.loaded.into_inner().unwrap(),
+            self.missing.into_inner().unwrap(),
+        )
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct MaterialData {
     pub path: String,
@@ -42,8 +86,12 @@ pub struct TextureData {
     pub image: DynamicImage,
 }
 
-#[instrument(skip(loader))]
-pub fn load_material(path: &str, loader: &Loader) -> Result<MaterialData, Error> {
+#[instrument(skip(loader, assets))]
+pub fn load_material(
+    path: &str,
+    loader: &Loader,
+    assets: &AssetLog,
+) -> Result<MaterialData, Error> {
     let path = if path.starts_with("materials/") {
         path.to_string()
     } else {
@@ -52,9 +100,7 @@ pub fn load_material(path: &str, loader: &Loader) -> Result<MaterialData, Error>
             path.to_ascii_lowercase().trim_end_matches(".vmt")
         )
     };
-    let raw = loader
-        .load(&path)?
-        .ok_or_else(|| Error::ResourceNotFound(path.clone()))?;
+    let raw = assets.load(loader, &path)?;
     let vdf = String::from_utf8(raw)?;
 
     let material = from_str(&vdf).map_err(|e| {
@@ -63,9 +109,7 @@ pub fn load_material(path: &str, loader: &Loader) -> Result<MaterialData, Error>
         Error::Other(format!("Failed to load material {}", path))
     })?;
     let material = material.resolve(|path| {
-        let data = loader
-            .load(path)?
-            .ok_or(Error::ResourceNotFound(path.into()))?;
+        let data = assets.load(loader, path)?;
         let vdf = String::from_utf8(data)?;
         Ok::<_, Error>(vdf)
     })?;
@@ -74,12 +118,15 @@ pub fn load_material(path: &str, loader: &Loader) -> Result<MaterialData, Error>
         base_texture: None, ..
     }) = &material
     {
-        return Ok(MaterialData {
+        let data = MaterialData {
             color: [82, 180, 217, 128],
             path,
             translucent: true,
             ..MaterialData::default()
-        });
+        };
+        #[cfg(feature = "dump_materials")]
+        dump_material(&material, &data);
+        return Ok(data);
     }
 
     let base_texture = material
@@ -89,21 +136,27 @@ pub fn load_material(path: &str, loader: &Loader) -> Result<MaterialData, Error>
     let translucent = material.translucent();
     let glass = material.surface_prop() == Some("glass");
     let alpha_test = material.alpha_test();
-    let texture = load_texture(base_texture, loader)?;
+    let texture = load_texture(base_texture, loader, assets)?;
 
-    let bump_map = material.bump_map().and_then(|path| {
-        Some(TextureData {
-            image: load_texture(path, loader).ok()?,
-            name: path.into(),
-        })
-    });
+    let bump_map = material
+        .bump_map()
+        .and_then(|path| match load_texture(path, loader, assets) {
+            Ok(image) => Some(TextureData {
+                image,
+                name: path.into(),
+            }),
+            Err(e) => {
+                assets.missing(path, &e);
+                None
+            }
+        });
 
     let transform = material
         .base_texture_transform()
         .filter(|transform| **transform != TextureTransform::default())
         .cloned();
 
-    Ok(MaterialData {
+    let data = MaterialData {
         color: [255; 4],
         path,
         texture: Some(TextureData {
@@ -114,15 +167,57 @@ pub fn load_material(path: &str, loader: &Loader) -> Result<MaterialData, Error>
         alpha_test,
         translucent: translucent | glass,
         transform,
-    })
+    };
+    #[cfg(feature = "dump_materials")]
+    dump_material(&material, &data);
+    Ok(data)
+}
+
+/// Write the resolved parameters and decoded textures of a material to `materials_dump/`
+#[cfg(feature = "dump_materials")]
+fn dump_material(material: &Material, data: &MaterialData) {
+    use std::path::{Component, Path, PathBuf};
+    use tracing::warn;
+
+    // the name comes from the map, so `..` and root components can't be allowed to leave the dump directory
+    let relative: PathBuf = Path::new(data.path.trim_end_matches(".vmt"))
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let base = Path::new("materials_dump").join(relative);
+    let file = |suffix: &str| {
+        let mut path = base.clone().into_os_string();
+        path.push(suffix);
+        path
+    };
+    let write = || -> Result<(), Error> {
+        if let Some(dir) = base.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let params = serde_json::to_string_pretty(material)?;
+        std::fs::write(file(".vmt.txt"), params)?;
+        for (suffix, texture) in [("", &data.texture), ("_bump", &data.bump_map)] {
+            if let Some(texture) = texture {
+                texture
+                    .image
+                    .to_rgba8()
+                    .save(file(&format!("{suffix}.png")))
+                    .map_err(|e| Error::Other(e.to_string()))?;
+            }
+        }
+        Ok(())
+    };
+    if let Err(e) = write() {
+        warn!(error = ?e, material = data.path, "failed to dump material");
+    }
 }
 
-fn load_texture(name: &str, loader: &Loader) -> Result<DynamicImage, Error> {
+fn load_texture(name: &str, loader: &Loader, assets: &AssetLog) -> Result<DynamicImage, Error> {
     let path = format!(
         "materials/{}.vtf",
         name.trim_end_matches(".vtf").trim_start_matches('/')
     );
-    let raw = loader.load(&path)?.ok_or(Error::ResourceNotFound(path))?;
+    let raw = assets.load(loader, &path)?;
     let vtf = VTF::read(&raw)?;
     let image = vtf.highres_image.decode(0)?;
     Ok(image)
@@ -0,0 +1,162 @@
+use crate::bsp::UNIT_SCALE;
+use crate::gltf::encode_png;
+use crate::Error;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use three_d::{CpuModel, CpuTexture, InnerSpace, Matrix, SquareMatrix};
+use three_d_asset::Geometry;
+use tracing::info;
+
+/// Write the models as a wavefront obj file, with the materials and textures next to it
+///
+/// Positions are in viewer units unless `hammer_units` is set
+pub fn export_obj(models: &[CpuModel], path: &Path, hammer_units: bool) -> Result<(), Error> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mtl_path = path.with_extension("mtl");
+    let scale = if hammer_units { 1.0 / UNIT_SCALE } else { 1.0 };
+
+    let textures: Vec<&CpuTexture> = models
+        .iter()
+        .flat_map(|model| &model.materials)
+        .flat_map(|material| [&material.albedo_texture, &material.normal_texture])
+        .flatten()
+        .collect();
+    let mut written = HashSet::new();
+    let textures: Vec<&CpuTexture> = textures
+        .into_iter()
+        .filter(|texture| written.insert(texture.name.as_str()))
+        .collect();
+    info!(textures = textures.len(), "writing textures");
+    textures.par_iter().try_for_each(|texture| {
+        if let Some(png) = encode_png(texture) {
+            fs::write(dir.join(texture_file(&texture.name)), png)?;
+        }
+        Ok::<_, std::io::Error>(())
+    })?;
+
+    let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+    let mut written = HashSet::new();
+    for material in models.iter().flat_map(|model| &model.materials) {
+        if !written.insert(material.name.as_str()) {
+            continue;
+        }
+        let color = material.albedo.to_linear_srgb();
+        writeln!(mtl, "newmtl {}", material_name(&material.name))?;
+        writeln!(mtl, "Kd {} {} {}", color.x, color.y, color.z)?;
+        if color.w < 1.0 {
+            writeln!(mtl, "d {}", color.w)?;
+        }
+        if let Some(texture) = &material.albedo_texture {
+            writeln!(mtl, "map_Kd {}", texture_file(&texture.name))?;
+        }
+        if let Some(texture) = &material.normal_texture {
+            writeln!(mtl, "norm {}", texture_file(&texture.name))?;
+        }
+        writeln!(mtl)?;
+    }
+    mtl.flush()?;
+
+    let mut obj = BufWriter::new(File::create(path)?);
+    if let Some(mtl_name) = mtl_path.file_name() {
+        writeln!(obj, "mtllib {}", mtl_name.to_string_lossy())?;
+    }
+    // obj indices are 1-based and global across the file, counted separately for positions, uvs and normals
+    let (mut position_offset, mut uv_offset, mut normal_offset) = (1, 1, 1);
+    for model in models {
+        for primitive in &model.geometries {
+            let Geometry::Triangles(mesh) = &primitive.geometry else {
+                continue;
+            };
+            let transform = primitive.transformation;
+            // normals keep facing away from the surface with non uniform scaling
+            let normal_transform = transform
+                .invert()
+                .map_or(transform, |inverse| inverse.transpose());
+            let positions = mesh.positions.to_f32();
+
+            writeln!(obj, "o {}_{}", model.name, primitive.name)?;
+            if let Some(material) = primitive
+                .material_index
+                .and_then(|index| model.materials.get(index))
+            {
+                writeln!(obj, "usemtl {}", material_name(&material.name))?;
+            }
+            for position in &positions {
+                let position = (transform * position.extend(1.0)).truncate() * scale;
+                writeln!(obj, "v {} {} {}", position.x, position.y, position.z)?;
+            }
+            let uvs = mesh.uvs.as_deref().unwrap_or_default();
+            for uv in uvs {
+                // obj has the uv origin in the bottom left
+                writeln!(obj, "vt {} {}", uv.x, 1.0 - uv.y)?;
+            }
+            let normals = mesh.normals.as_deref().unwrap_or_default();
+            for normal in normals {
+                let normal = (normal_transform * normal.extend(0.0))
+                    .truncate()
+                    .normalize();
+                writeln!(obj, "vn {} {} {}", normal.x, normal.y, normal.z)?;
+            }
+
+            let vertex = |index: u32| {
+                let index = index as usize;
+                let position = index + position_offset;
+                let (uv, normal) = (index + uv_offset, index + normal_offset);
+                match (uvs.is_empty(), normals.is_empty()) {
+                    (false, false) => format!("{position}/{uv}/{normal}"),
+                    (false, true) => format!("{position}/{uv}"),
+                    (true, false) => format!("{position}//{normal}"),
+                    (true, true) => format!("{position}"),
+                }
+            };
+            let indices = mesh
+                .indices
+                .to_u32()
+                .unwrap_or_else(|| (0..positions.len() as u32).collect());
+            for triangle in indices.chunks_exact(3) {
+                writeln!(
+                    obj,
+                    "f {} {} {}",
+                    vertex(triangle[0]),
+                    vertex(triangle[1]),
+                    vertex(triangle[2])
+                )?;
+            }
+            position_offset += positions.len();
+            uv_offset += uvs.len();
+            normal_offset += normals.len();
+        }
+    }
+    obj.flush()?;
+
+    Ok(())
+}
+
+/// Material names can't contain whitespace in mtl files
+fn material_name(name: &str) -> String {
+    name.replace(char::is_whitespace, "_")
+}
+
+/// Flat file name for a texture path
+fn texture_file(name: &str) -> String {
+    let name = name
+        .trim_start_matches('/')
+        .trim_end_matches(".vtf")
+        .to_ascii_lowercase();
+    format!(
+        "{}.png",
+        name.replace(|c: char| c == '/' || c == '\\' || c.is_whitespace(), "_")
+    )
+}
+
+#[test]
+fn test_texture_file() {
+    assert_eq!(
+        "concrete_concretefloor001a.png",
+        texture_file("Concrete/ConcreteFloor001a")
+    );
+    assert_eq!("skybox_sky_up.png", texture_file("/skybox\\sky up.vtf"));
+}
@@ -0,0 +1,210 @@
+use crate::bsp::UNIT_SCALE;
+use crate::control::DemoPlayback;
+use crate::demo::PlayerPath;
+use crate::loader::Loader;
+use crate::material::{AssetLog, MaterialSet};
+use crate::prop::{load_prop, prop_texture_to_material, prop_to_meshes, PropData};
+use std::rc::Rc;
+use tf_demo_parser::demo::parser::analyser::{Class, Team};
+use three_d::*;
+use three_d_asset::Geometry;
+use tracing::error;
+
+pub type EntityMesh = Gm<InstancedMesh, PhysicalMaterial>;
+
+/// The players of a demo, drawn with their class model at the playback tick
+pub struct DemoPlayers {
+    players: Rc<[PlayerPath]>,
+    models: Vec<ClassModel>,
+}
+
+/// The meshes for a class and team, with an instance for every player currently playing it
+struct ClassModel {
+    class: Class,
+    team: Team,
+    meshes: Vec<EntityMesh>,
+    visible: bool,
+}
+
+impl DemoPlayers {
+    /// Load the class models, players of classes without a model are drawn as a hull in their team color
+    pub fn new(context: &Context, loader: &Loader, players: Rc<[PlayerPath]>) -> Self {
+        let assets = AssetLog::default();
+        let mut models = Vec::new();
+        for class in (1..=9).map(Class::new) {
+            let name = format!("models/player/{}.mdl", class_model(class));
+            // the player hull is 48 by 48 by 82 hammer units
+            let meshes = team_meshes(context, loader, &assets, &name, vec3(48.0, 82.0, 48.0));
+            for (team, meshes) in [Team::Red, Team::Blue].into_iter().zip(meshes) {
+                models.push(ClassModel {
+                    class,
+                    team,
+                    meshes,
+                    visible: false,
+                });
+            }
+        }
+
+        DemoPlayers { players, models }
+    }
+
+    /// Place the players at their position for the playback tick
+    pub fn update(&mut self, playback: DemoPlayback) {
+        let mut transformations = vec![Vec::new(); self.models.len()];
+        for player in self.players.iter() {
+            if Some(player.track.entity) == playback.pov_player {
+                continue;
+            }
+            let state = player.track.state(playback.tick);
+            if !state.alive || !state.in_pvs {
+                continue;
+            }
+            let Some(model) = self
+                .models
+                .iter()
+                .position(|model| model.class == state.class && model.team == state.team)
+            else {
+                continue;
+            };
+            let Some(position) = player.position(playback.tick) else {
+                continue;
+            };
+            let yaw = player.yaw(playback.tick);
+            transformations[model]
+                .push(Mat4::from_translation(position) * Mat4::from_angle_y(degrees(yaw)));
+        }
+
+        for (model, transformations) in self.models.iter_mut().zip(transformations) {
+            model.visible = !transformations.is_empty();
+            set_transformations(&mut model.meshes, transformations);
+        }
+    }
+
+    /// The meshes with players in them
+    pub fn meshes(&self) -> impl Iterator<Item = &EntityMesh> {
+        self.models
+            .iter()
+            .filter(|model| model.visible)
+            .flat_map(|model| &model.meshes)
+    }
+}
+
+/// Name of the model file of a class
+fn class_model(class: Class) -> &'static str {
+    match class {
+        Class::Scout => "scout",
+        Class::Sniper => "sniper",
+        Class::Soldier => "soldier",
+        Class::Demoman => "demo",
+        Class::Medic => "medic",
+        Class::Heavy => "heavy",
+        Class::Pyro => "pyro",
+        Class::Spy => "spy",
+        Class::Engineer | Class::Other => "engineer",
+    }
+}
+
+/// Meshes for the red and blue variants of a model
+///
+/// Models that can't be loaded are replaced by a box in the team color, `size` hammer units large with the origin at the bottom
+pub fn team_meshes(
+    context: &Context,
+    loader: &Loader,
+    assets: &AssetLog,
+    name: &str,
+    size: Vec3,
+) -> [Vec<EntityMesh>; 2] {
+    let prop = match load_prop(loader, name, assets) {
+        Ok(prop) => Some(prop),
+        Err(e) => {
+            error!(error = ?e, model = name, "failed to load model");
+            None
+        }
+    };
+    // team colored models have the red skin first, then blue
+    [Team::Red, Team::Blue].map(|team| match &prop {
+        Some(prop) => {
+            let data = PropData {
+                name,
+                prop,
+                skin: if team == Team::Blue { 1 } else { 0 },
+                body: 0,
+            };
+            model_meshes(context, loader, assets, &data)
+        }
+        None => vec![hull_mesh(context, team, size)],
+    })
+}
+
+/// Draw the meshes at every transformation
+pub fn set_transformations(meshes: &mut [EntityMesh], transformations: Vec<Mat4>) {
+    let instances = Instances {
+        transformations,
+        ..Instances::default()
+    };
+    for mesh in meshes {
+        mesh.geometry.set_instances(&instances);
+    }
+}
+
+fn team_color(team: Team) -> Srgba {
+    match team {
+        Team::Red => Srgba::new_opaque(184, 56, 59),
+        Team::Blue => Srgba::new_opaque(88, 133, 162),
+        _ => Srgba::new_opaque(128, 128, 128),
+    }
+}
+
+fn model_meshes(
+    context: &Context,
+    loader: &Loader,
+    assets: &AssetLog,
+    data: &PropData<'_>,
+) -> Vec<EntityMesh> {
+    let used_materials = MaterialSet::new(loader);
+    let primitives: Vec<_> = prop_to_meshes(data, 0, &used_materials, true).collect();
+    let materials: Vec<_> = used_materials
+        .into_materials()
+        .iter()
+        .map(|material| {
+            PhysicalMaterial::new(context, &prop_texture_to_material(material, loader, assets))
+        })
+        .collect();
+    primitives
+        .iter()
+        .filter_map(|primitive| match &primitive.geometry {
+            Geometry::Triangles(mesh) => Some((primitive, mesh)),
+            _ => None,
+        })
+        .map(|(primitive, mesh)| {
+            let material = primitive
+                .material_index
+                .and_then(|index| materials.get(index))
+                .cloned()
+                .unwrap_or_default();
+            Gm::new(
+                InstancedMesh::new(context, &Instances::default(), mesh),
+                material,
+            )
+        })
+        .collect()
+}
+
+/// A box of `size` hammer units with the origin at the center of the bottom
+fn hull_mesh(context: &Context, team: Team, size: Vec3) -> EntityMesh {
+    let half = size * UNIT_SCALE / 2.0;
+    let mut mesh = CpuMesh::cube();
+    mesh.transform(
+        &(Mat4::from_translation(vec3(0.0, half.y, 0.0))
+            * Mat4::from_nonuniform_scale(half.x, half.y, half.z)),
+    )
+    .expect("the hull transform is invertible");
+    let material = CpuMaterial {
+        albedo: team_color(team),
+        ..CpuMaterial::default()
+    };
+    Gm::new(
+        InstancedMesh::new(context, &Instances::default(), &mesh),
+        PhysicalMaterial::new_opaque(context, &material),
+    )
+}
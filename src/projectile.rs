@@ -0,0 +1,123 @@
+use crate::demo::{DemoObject, ObjectKind};
+use crate::loader::Loader;
+use crate::material::AssetLog;
+use crate::player::{set_transformations, team_meshes, EntityMesh};
+use splines::Spline;
+use tf_demo_parser::demo::parser::analyser::Team;
+use three_d::*;
+
+/// The projectiles and buildings of a demo, drawn at the playback tick
+pub struct DemoObjects {
+    objects: Vec<ObjectPath>,
+    models: Vec<ObjectModel>,
+}
+
+struct ObjectPath {
+    object: DemoObject,
+    positions: Spline<f32, Vec3>,
+}
+
+/// The meshes for a kind of object and team, with an instance for every object currently there
+struct ObjectModel {
+    kind: ObjectKind,
+    team: Team,
+    meshes: Vec<EntityMesh>,
+    visible: bool,
+}
+
+impl DemoObjects {
+    pub fn new(context: &Context, loader: &Loader, objects: Vec<DemoObject>) -> Self {
+        let assets = AssetLog::default();
+        let mut models = Vec::new();
+        for kind in ObjectKind::ALL {
+            let (name, size) = object_model(kind);
+            let meshes = team_meshes(context, loader, &assets, name, size);
+            for (team, meshes) in [Team::Red, Team::Blue].into_iter().zip(meshes) {
+                models.push(ObjectModel {
+                    kind,
+                    team,
+                    meshes,
+                    visible: false,
+                });
+            }
+        }
+
+        let objects = objects
+            .into_iter()
+            .map(|object| ObjectPath {
+                positions: Spline::from_vec(object.positions.clone()),
+                object,
+            })
+            .collect();
+        DemoObjects { objects, models }
+    }
+
+    /// Place the objects that exist at the playback tick
+    pub fn update(&mut self, tick: f64) {
+        let mut transformations = vec![Vec::new(); self.models.len()];
+        for path in &self.objects {
+            let object = &path.object;
+            if !object.is_alive(tick) {
+                continue;
+            }
+            let Some(model) = self
+                .models
+                .iter()
+                .position(|model| model.kind == object.kind && model.team == object.team)
+            else {
+                continue;
+            };
+            let Some(position) = path.positions.clamped_sample(tick as f32) else {
+                continue;
+            };
+            let [pitch, yaw, _roll] = object.angles(tick);
+            transformations[model].push(
+                Mat4::from_translation(position)
+                    * Mat4::from_angle_y(degrees(yaw))
+                    * Mat4::from_angle_x(degrees(pitch)),
+            );
+        }
+
+        for (model, transformations) in self.models.iter_mut().zip(transformations) {
+            model.visible = !transformations.is_empty();
+            set_transformations(&mut model.meshes, transformations);
+        }
+    }
+
+    /// The meshes with objects in them
+    pub fn meshes(&self) -> impl Iterator<Item = &EntityMesh> {
+        self.models
+            .iter()
+            .filter(|model| model.visible)
+            .flat_map(|model| &model.meshes)
+    }
+}
+
+/// The model of a kind of object, and the size in hammer units of the box drawn if it can't be loaded
+fn object_model(kind: ObjectKind) -> (&'static str, Vec3) {
+    match kind {
+        ObjectKind::Rocket => ("models/weapons/w_models/w_rocket.mdl", vec3(6.0, 6.0, 24.0)),
+        ObjectKind::Pipe => (
+            "models/weapons/w_models/w_grenade_grenadelauncher.mdl",
+            vec3(6.0, 6.0, 10.0),
+        ),
+        ObjectKind::Sticky => (
+            "models/weapons/w_models/w_stickybomb.mdl",
+            vec3(8.0, 4.0, 8.0),
+        ),
+        ObjectKind::Arrow => ("models/weapons/w_models/w_arrow.mdl", vec3(2.0, 2.0, 30.0)),
+        ObjectKind::Flare => (
+            "models/weapons/w_models/w_flaregun_shell.mdl",
+            vec3(4.0, 4.0, 8.0),
+        ),
+        ObjectKind::Sentry => ("models/buildables/sentry1.mdl", vec3(40.0, 66.0, 40.0)),
+        ObjectKind::Dispenser => (
+            "models/buildables/dispenser_light.mdl",
+            vec3(48.0, 82.0, 48.0),
+        ),
+        ObjectKind::Teleporter => (
+            "models/buildables/teleporter_light.mdl",
+            vec3(48.0, 12.0, 48.0),
+        ),
+    }
+}
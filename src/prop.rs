@@ -1,121 +1,1062 @@
-use crate::bsp::map_coords;
-use crate::material::{convert_material, load_material_fallback, MaterialSet};
+use crate::animation::{PropAnimation, SkinnedMesh, SkinnedVertex};
+use crate::bsp::{map_coords, UNIT_SCALE};
+use crate::loader::Loader;
+use crate::material::{convert_material, load_material_fallback, AssetLog, MaterialSet};
+use crate::vis::cluster_visible;
 use crate::Error;
 use rayon::prelude::*;
-use tf_asset_loader::Loader;
-use three_d::{CpuMaterial, CpuModel, Mat4, Positions, Vec2, Vec3, Vec4};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use three_d::{
+    AxisAlignedBoundingBox, Blend, Camera, ColorTexture, Context, CpuMaterial, CpuModel,
+    DepthTexture, Effect, FragmentAttributes, Gm, InstancedMesh, Instances, Light, Mat4, Material,
+    PhysicalMaterial, Positions, Program, RenderStates, SquareMatrix, Srgba, Transform, Vec2, Vec3,
+    Vec4, WriteMask,
+};
 use three_d_asset::{Geometry, Primitive, TriMesh};
 use tracing::{error, warn};
-use vbsp::PropPlacement;
-use vmdl::mdl::Mdl;
+use vbsp::{Bsp, PropPlacement};
+use vmdl::mdl::{Animation, Mdl, ModelFlags, TextureInfo};
 use vmdl::vtx::Vtx;
-use vmdl::vvd::Vvd;
-
-#[tracing::instrument(skip(loader))]
-pub fn load_prop(loader: &Loader, name: &str) -> Result<vmdl::Model, Error> {
-    let load = |name: &str| -> Result<Vec<u8>, Error> {
-        loader
-            .load(name)?
-            .ok_or(Error::ResourceNotFound(name.into()))
-    };
-    let mdl = Mdl::read(&load(name)?)?;
+use vmdl::vvd::{Vertex, Vvd};
+use vmdl::Quaternion;
+
+/// A loaded model
+///
+/// The mdl and vtx are kept as parsed since `vmdl::Model` doesn't expose the levels of detail and bodygroups
+pub struct LoadedProp {
+    pub mdl: Mdl,
+    pub vtx: Vtx,
+    pub vvd: Vvd,
+    /// Number of material slots in every skin table
+    skin_references: usize,
+    /// Index of the local animation played by every sequence
+    sequence_animations: Vec<Option<usize>>,
+}
+
+impl LoadedProp {
+    /// Switch point of every level of detail, in the game's lod metric
+    pub fn lod_switch_points(&self) -> Vec<f32> {
+        self.vtx
+            .body_parts
+            .iter()
+            .flat_map(|part| &part.models)
+            .find(|model| !model.lods.is_empty())
+            .map(|model| model.lods.iter().map(|lod| lod.switch_point).collect())
+            .unwrap_or_else(|| vec![0.0])
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vvd.vertices
+    }
+
+    pub fn tangents(&self) -> &[[f32; 4]] {
+        &self.vvd.tangents
+    }
+
+    /// Number of skins, models without skin tables have a single skin
+    pub fn skin_count(&self) -> usize {
+        match self.skin_references {
+            0 => 1,
+            references => self.mdl.skin_table.len().div_ceil(references).max(1),
+        }
+    }
+
+    /// The texture a skin uses for a material slot
+    pub fn skin_texture(&self, skin: usize, material: i32) -> Option<&TextureInfo> {
+        let table = self
+            .mdl
+            .skin_table
+            .chunks(self.skin_references.max(1))
+            .nth(skin)
+            .filter(|_| self.skin_references > 0)?;
+        let texture = table.get(usize::try_from(material).ok()?)?;
+        self.mdl.textures.get(*texture as usize)
+    }
+
+    /// The local animation a sequence plays, the first one of its blends
+    pub fn sequence_animation(&self, sequence: usize) -> Option<usize> {
+        self.sequence_animations.get(sequence).copied().flatten()
+    }
+
+    /// Rotation into the idle pose, which the model vertices have to be transformed with
+    ///
+    /// Static props are compiled in their final pose and don't have one
+    pub fn root_transform(&self) -> Mat4 {
+        if self.mdl.header.flags.contains(ModelFlags::STATIC_PROP) {
+            return Mat4::identity();
+        }
+        let root = self
+            .mdl
+            .bones
+            .first()
+            .map_or_else(Mat4::identity, |bone| Mat4::from(bone.rot));
+        // like `vmdl::Model::idle_transform`, animations with a broken first rotation are skipped
+        let idle = self
+            .mdl
+            .local_animations
+            .iter()
+            .filter_map(|animation| animation.animations.iter().find(|a| a.bone == 0))
+            .find(|animation| rotation_looks_valid(animation))
+            .map_or_else(Mat4::identity, |animation| {
+                Mat4::from(animation.rotation(0))
+            });
+        idle * root
+    }
+}
+
+/// Whether the first frame of a bone animation is a usable rotation, a unit quaternion
+fn rotation_looks_valid(animation: &Animation) -> bool {
+    let Quaternion { x, y, z, w } = animation.rotation(0);
+    let length = (x * x + y * y + z * z + w * w).sqrt();
+    length.is_finite() && (length - 1.0).abs() < 0.01
+}
+
+#[tracing::instrument(skip(loader, assets))]
+pub fn load_prop(loader: &Loader, name: &str, assets: &AssetLog) -> Result<LoadedProp, Error> {
+    let load = |name: &str| assets.load(loader, name);
+    let mdl_data = load(name)?;
+    let mdl = Mdl::read(&mdl_data)?;
     let vtx = Vtx::read(&load(&name.replace(".mdl", ".dx90.vtx"))?)?;
     let vvd = Vvd::read(&load(&name.replace(".mdl", ".vvd"))?)?;
 
-    Ok(vmdl::Model::from_parts(mdl, vtx, vvd))
+    Ok(LoadedProp {
+        skin_references: skin_references(&mdl_data),
+        sequence_animations: sequence_animations(&mdl_data),
+        mdl,
+        vtx,
+        vvd,
+    })
+}
+
+/// Number of material slots in every skin table, `vmdl` only uses it internally so this reads it from the mdl header
+fn skin_references(mdl: &[u8]) -> usize {
+    // offset of `numskinref` in `studiohdr_t`
+    const OFFSET: usize = 220;
+    read_i32(mdl, OFFSET)
+        .and_then(|count| usize::try_from(count).ok())
+        .unwrap_or_default()
 }
-pub fn load_props<'a, I: Iterator<Item = PropPlacement<'a>>>(
+
+/// Index of the first local animation of every sequence, `vmdl` doesn't keep it so this reads the sequence descriptions
+fn sequence_animations(mdl: &[u8]) -> Vec<Option<usize>> {
+    // offsets of `numlocalseq` and `localseqindex` in `studiohdr_t`
+    const COUNT_OFFSET: usize = 188;
+    const SEQUENCES_OFFSET: usize = 192;
+    // size of `mstudioseqdesc_t` and offset of its `animindexindex`
+    const SEQUENCE_SIZE: usize = 212;
+    const ANIMATION_INDEX_OFFSET: usize = 60;
+
+    let count = read_i32(mdl, COUNT_OFFSET).and_then(|count| usize::try_from(count).ok());
+    let start = read_i32(mdl, SEQUENCES_OFFSET).and_then(|offset| usize::try_from(offset).ok());
+    let (Some(count), Some(start)) = (count, start) else {
+        return Vec::new();
+    };
+    (0..count)
+        .map(|i| {
+            let sequence = start + i * SEQUENCE_SIZE;
+            let index = read_i32(mdl, sequence + ANIMATION_INDEX_OFFSET)?;
+            // the animations of the blends are stored as shorts, the first one is the unblended animation
+            let offset = sequence + usize::try_from(index).ok()?;
+            let bytes = mdl.get(offset..offset + 2)?;
+            usize::try_from(i16::from_le_bytes(bytes.try_into().ok()?)).ok()
+        })
+        .collect()
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Option<i32> {
+    data.get(offset..offset + 4)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(i32::from_le_bytes)
+}
+
+#[test]
+fn test_sequence_animations() {
+    let mut mdl = vec![0; 300 + 2 * 212 + 8];
+    mdl[188..192].copy_from_slice(&2i32.to_le_bytes());
+    mdl[192..196].copy_from_slice(&300i32.to_le_bytes());
+    for (i, animation) in [3i16, 1].into_iter().enumerate() {
+        let sequence = 300 + i * 212;
+        let index = (2 * 212 - i * 212 + i * 2) as i32;
+        mdl[sequence + 60..sequence + 64].copy_from_slice(&index.to_le_bytes());
+        let offset = sequence + index as usize;
+        mdl[offset..offset + 2].copy_from_slice(&animation.to_le_bytes());
+    }
+    assert_eq!(vec![Some(3), Some(1)], sequence_animations(&mdl));
+    assert_eq!(Vec::<Option<usize>>::new(), sequence_animations(&[]));
+}
+
+/// The map wide screen space prop fade from the worldspawn, as the widths in pixels where props start and finish fading
+pub fn load_screen_fade(bsp: &Bsp) -> Option<(f32, f32)> {
+    let world = bsp
+        .entities
+        .iter()
+        .find(|ent| ent.prop("classname") == Some("worldspawn"))?;
+    let float = |key| world.prop_parse::<f32>(key).and_then(Result::ok);
+    let (min, max) = (
+        float("minpropscreenwidth").unwrap_or(0.0),
+        float("maxpropscreenwidth").unwrap_or(-1.0),
+    );
+    // props fade as they get smaller, so the start of the fade is the larger width
+    let (start, end) = (min.max(max), min.min(max).max(0.0));
+    (start > 0.0).then_some((start, end))
+}
+
+/// A prop placed in the map, with the properties that aren't part of the placement
+pub struct MapProp<'a> {
+    pub placement: PropPlacement<'a>,
+    pub fade: PropFade,
+    /// Bodygroup selection, encoded like the `body` keyvalue
+    pub body: i32,
+    /// Sequence to play, from the `DefaultAnim` keyvalue
+    pub animation: Option<&'a str>,
+}
+
+pub fn load_props<'a, I: Iterator<Item = MapProp<'a>>>(
     loader: &Loader,
     props: I,
     show_textures: bool,
-) -> Result<Vec<CpuModel>, Error> {
-    let props: Vec<_> = props
-        .filter_map(|prop| match load_prop(loader, prop.model) {
-            Ok(model) => Some((prop, model)),
+    assets: &AssetLog,
+) -> Result<Props, Error> {
+    // placements are grouped by model, skin and body, so every model only has to be uploaded once
+    // animated props are kept apart since they each play their animation separately
+    let mut placements: BTreeMap<&str, BTreeMap<PropVariant, Vec<PropInstance>>> = BTreeMap::new();
+    for (
+        index,
+        MapProp {
+            placement: prop,
+            fade,
+            body,
+            animation,
+        },
+    ) in props.enumerate()
+    {
+        let transform = Mat4::from_translation(map_coords(prop.origin))
+            * Mat4::from(prop.rotation)
+            * Mat4::from_scale(prop.scale);
+        placements
+            .entry(prop.model)
+            .or_default()
+            .entry((prop.skin, body, animation.map(|sequence| (index, sequence))))
+            .or_default()
+            .push(PropInstance {
+                transform,
+                cluster: -1,
+                fade,
+            });
+    }
+
+    let props: Vec<_> = placements
+        .into_iter()
+        .filter_map(|(name, variants)| match load_prop(loader, name, assets) {
+            Ok(prop) => Some((name, prop, variants)),
             Err(e) => {
-                error!(error = ?e, prop = prop.model, "Failed to load prop");
+                error!(error = ?e, prop = name, "Failed to load prop");
+                assets.missing(name, &e);
                 None
             }
         })
-        .map(|(prop, model)| {
-            let transform = Mat4::from_translation(map_coords(prop.origin))
-                * Mat4::from(prop.rotation)
-                * Mat4::from_scale(prop.scale);
-            PropData {
-                name: prop.model,
-                model,
-                transform,
-                skin: prop.skin,
-            }
-        })
         .collect();
 
     let used_materials = MaterialSet::new(loader);
 
-    let geometries = props
+    let models = props
         .iter()
-        .flat_map(|prop| prop_to_meshes(prop, &used_materials, show_textures))
+        .flat_map(|(name, prop, variants)| {
+            let switch_points = prop.lod_switch_points();
+            let used_materials = &used_materials;
+            variants
+                .iter()
+                .map(move |((skin, body, animation), instances)| {
+                    let data = PropData {
+                        name,
+                        prop,
+                        skin: *skin,
+                        body: *body,
+                    };
+                    let animation = animation
+                        .and_then(|(_, sequence)| PropAnimation::new(prop, name, sequence));
+                    let lods = switch_points
+                        .iter()
+                        .enumerate()
+                        .map(|(lod, switch_point)| PropLod {
+                            switch_point: *switch_point,
+                            geometries: prop_to_meshes(&data, lod, used_materials, show_textures)
+                                .collect(),
+                            skins: if animation.is_some() {
+                                skinned_vertices(prop, *body, lod)
+                            } else {
+                                Vec::new()
+                            },
+                        })
+                        .collect();
+                    PropModel {
+                        lods,
+                        instances: instances.clone(),
+                        animation,
+                    }
+                })
+        })
         .collect();
 
     let materials = used_materials
         .into_materials()
         .into_par_iter()
-        .map(|mat| prop_texture_to_material(&mat, loader))
+        .map(|mat| prop_texture_to_material(&mat, loader, assets))
         .collect();
 
-    Ok(vec![CpuModel {
-        name: "props".into(),
-        geometries,
+    Ok(Props {
         materials,
-    }])
+        models,
+        screen_fade: None,
+    })
 }
 
-struct PropData<'a> {
-    name: &'a str,
-    model: vmdl::Model,
-    transform: Mat4,
-    skin: i32,
+/// Skin, body and for animated props the placement index and sequence
+type PropVariant<'a> = (i32, i32, Option<(usize, &'a str)>);
+
+/// All props placed in the map
+#[derive(Default)]
+pub struct Props {
+    pub materials: Vec<CpuMaterial>,
+    pub models: Vec<PropModel>,
+    /// Screen width in pixels where all props start and finish fading, from the worldspawn
+    pub screen_fade: Option<(f32, f32)>,
+}
+
+impl Props {
+    /// Combine every placement into a single model, for exporting
+    pub fn to_model(&self) -> CpuModel {
+        let geometries = self
+            .models
+            .iter()
+            .flat_map(|model| {
+                // only the most detailed level is exported
+                let geometries = model
+                    .lods
+                    .first()
+                    .map(|lod| lod.geometries.as_slice())
+                    .unwrap_or_default();
+                model.instances.iter().flat_map(move |instance| {
+                    geometries.iter().map(|primitive| Primitive {
+                        transformation: instance.transform * primitive.transformation,
+                        ..primitive.clone()
+                    })
+                })
+            })
+            .collect();
+        CpuModel {
+            name: "props".into(),
+            geometries,
+            materials: self.materials.clone(),
+        }
+    }
+}
+
+/// A prop model with a specific skin and bodygroups, and everywhere it's placed
+pub struct PropModel {
+    /// The levels of detail, from most to least detailed
+    pub lods: Vec<PropLod>,
+    pub instances: Vec<PropInstance>,
+    pub animation: Option<PropAnimation>,
+}
+
+pub struct PropLod {
+    /// Lod metric from which this level is used
+    pub switch_point: f32,
+    /// Meshes in model space, the material indices point into [`Props::materials`]
+    pub geometries: Vec<Primitive>,
+    /// Vertices of every geometry with their bone weights, only for animated props
+    pub skins: Vec<Vec<SkinnedVertex>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PropInstance {
+    pub transform: Mat4,
+    /// The vis cluster containing the prop origin, or -1 if unknown
+    pub cluster: i16,
+    pub fade: PropFade,
+}
+
+/// Fade settings of a prop, from `fademindist`, `fademaxdist` and the fade scale
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PropFade {
+    /// Distances in viewer units where the prop starts and finishes fading out
+    pub distance: Option<(f32, f32)>,
+    /// Scale for the fade distances and the map's screen space fade, 0 disables the screen space fade
+    pub scale: f32,
+}
+
+impl Default for PropFade {
+    fn default() -> Self {
+        PropFade {
+            distance: None,
+            scale: 1.0,
+        }
+    }
+}
+
+impl PropFade {
+    /// Fade settings from the distances in hammer units
+    ///
+    /// A max distance of 0 or lower disables the distance fade, a negative min distance makes the prop disappear at the max distance
+    pub fn new(min: f32, max: f32, scale: f32) -> Self {
+        let min = if min < 0.0 { max } else { min.min(max) };
+        PropFade {
+            distance: (max > 0.0).then_some((min * UNIT_SCALE, max * UNIT_SCALE)),
+            scale,
+        }
+    }
+
+    /// Opacity of the prop at a distance from the camera, covering `screen_width` pixels
+    ///
+    /// `screen_fade` is the map wide range in pixels where props start and finish fading
+    pub fn opacity(
+        &self,
+        distance: f32,
+        screen_width: f32,
+        screen_fade: Option<(f32, f32)>,
+    ) -> f32 {
+        let distance_opacity = match self.distance {
+            Some((min, max)) => {
+                let distance = distance * if self.scale > 0.0 { self.scale } else { 1.0 };
+                fade_opacity(distance, min, max)
+            }
+            None => 1.0,
+        };
+        let screen_opacity = match screen_fade {
+            Some((start, end)) if self.scale > 0.0 => {
+                // fading happens as the prop gets smaller, so this is a fade over the negated width
+                fade_opacity(-screen_width / self.scale, -start, -end)
+            }
+            _ => 1.0,
+        };
+        distance_opacity.min(screen_opacity)
+    }
+}
+
+/// Linear fade from fully opaque at `start` to invisible at `end`
+fn fade_opacity(value: f32, start: f32, end: f32) -> f32 {
+    if end <= start {
+        if value < end {
+            1.0
+        } else {
+            0.0
+        }
+    } else {
+        1.0 - ((value - start) / (end - start)).clamp(0.0, 1.0)
+    }
+}
+
+#[test]
+fn test_prop_fade() {
+    let fade = PropFade::new(100.0, 200.0, 1.0);
+    assert_eq!(1.0, fade.opacity(50.0 * UNIT_SCALE, 100.0, None));
+    assert!((fade.opacity(150.0 * UNIT_SCALE, 100.0, None) - 0.5).abs() < 0.001);
+    assert_eq!(0.0, fade.opacity(250.0 * UNIT_SCALE, 100.0, None));
+
+    // a larger scale makes the prop fade closer to the camera
+    let scaled = PropFade::new(100.0, 200.0, 2.0);
+    assert_eq!(0.0, scaled.opacity(150.0 * UNIT_SCALE, 100.0, None));
+
+    let screen = PropFade::default();
+    assert_eq!(1.0, screen.opacity(1000.0, 100.0, Some((50.0, 10.0))));
+    assert!((screen.opacity(1000.0, 30.0, Some((50.0, 10.0))) - 0.5).abs() < 0.001);
+    assert_eq!(0.0, screen.opacity(1000.0, 5.0, Some((50.0, 10.0))));
+    let no_screen_fade = PropFade::new(0.0, 0.0, 0.0);
+    assert_eq!(1.0, no_screen_fade.opacity(1000.0, 5.0, Some((50.0, 10.0))));
+}
+
+pub type PropMesh = RefCell<Gm<PropGeometry, PhysicalMaterial>>;
+
+/// The gpu mesh of a prop, animated props are skinned in the vertex shader
+pub enum PropGeometry {
+    Static(InstancedMesh),
+    Skinned(SkinnedMesh),
 }
 
-fn prop_to_meshes<'a>(
+impl PropGeometry {
+    fn set_instances(&mut self, instances: &Instances) {
+        match self {
+            PropGeometry::Static(mesh) => mesh.set_instances(instances),
+            PropGeometry::Skinned(mesh) => mesh.set_instances(instances),
+        }
+    }
+
+    /// Pose an animated mesh, does nothing for static meshes
+    fn set_bones(&mut self, bones: &[Mat4]) {
+        if let PropGeometry::Skinned(mesh) = self {
+            mesh.set_bones(bones);
+        }
+    }
+
+    fn inner(&self) -> &dyn three_d::Geometry {
+        match self {
+            PropGeometry::Static(mesh) => mesh,
+            PropGeometry::Skinned(mesh) => mesh,
+        }
+    }
+}
+
+impl three_d::Geometry for PropGeometry {
+    fn draw(
+        &self,
+        camera: &Camera,
+        program: &Program,
+        render_states: RenderStates,
+        attributes: FragmentAttributes,
+    ) {
+        self.inner()
+            .draw(camera, program, render_states, attributes)
+    }
+
+    fn vertex_shader_source(&self, required_attributes: FragmentAttributes) -> String {
+        self.inner().vertex_shader_source(required_attributes)
+    }
+
+    fn id(&self, required_attributes: FragmentAttributes) -> u16 {
+        self.inner().id(required_attributes)
+    }
+
+    fn render_with_material(
+        &self,
+        material: &dyn Material,
+        camera: &Camera,
+        lights: &[&dyn Light],
+    ) {
+        self.inner().render_with_material(material, camera, lights)
+    }
+
+    fn render_with_effect(
+        &self,
+        material: &dyn Effect,
+        camera: &Camera,
+        lights: &[&dyn Light],
+        color_texture: Option<ColorTexture>,
+        depth_texture: Option<DepthTexture>,
+    ) {
+        self.inner()
+            .render_with_effect(material, camera, lights, color_texture, depth_texture)
+    }
+
+    fn aabb(&self) -> AxisAlignedBoundingBox {
+        self.inner().aabb()
+    }
+}
+
+/// A prop model on the gpu, drawing all selected instances with a single draw call per mesh
+///
+/// Instances that are fading out are drawn separately with a blended copy of the materials
+pub struct InstancedProp {
+    lods: Vec<InstancedLod>,
+    switch_points: Vec<f32>,
+    instances: Vec<PropInstance>,
+    /// World space bounds of every instance
+    bounds: Vec<AxisAlignedBoundingBox>,
+    animator: Option<PropAnimator>,
+}
+
+/// The render passes that select their own prop instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    Main,
+    /// The 3d skybox, seen from the sky camera
+    Sky,
+}
+
+/// Playback state of an animated prop
+pub struct PropAnimator {
+    pub animation: PropAnimation,
+    pub playing: bool,
+    /// Playback position in seconds
+    time: f32,
+    /// The current skinning matrices
+    bones: Vec<Mat4>,
+}
+
+struct InstancedLod {
+    main: LodInstances,
+    /// Only for props that can be seen from the sky camera
+    sky: Option<LodInstances>,
+}
+
+/// The meshes of a level of detail with the instances uploaded for one pass
+struct LodInstances {
+    meshes: Vec<PropMesh>,
+    fading_meshes: Vec<PropMesh>,
+    /// Indices of the opaque instances currently uploaded
+    selected: RefCell<Vec<usize>>,
+    /// Indices and opacity of the fading instances currently uploaded
+    fading: RefCell<Vec<(usize, u8)>>,
+}
+
+impl InstancedLod {
+    fn pass(&self, pass: Pass) -> Option<&LodInstances> {
+        match pass {
+            Pass::Main => Some(&self.main),
+            Pass::Sky => self.sky.as_ref(),
+        }
+    }
+
+    fn passes(&self) -> impl Iterator<Item = &LodInstances> {
+        std::iter::once(&self.main).chain(&self.sky)
+    }
+}
+
+/// A mesh of a level of detail with its material, and its vertices with their bones for animated props
+type MeshData<'a> = (&'a TriMesh, Option<&'a [SkinnedVertex]>, PhysicalMaterial);
+
+impl LodInstances {
+    fn new(
+        context: &Context,
+        meshes: &[MeshData],
+        instances: &[PropInstance],
+        selected: Vec<usize>,
+        bones: &[Mat4],
+    ) -> Self {
+        let opaque = instance_data(selected.iter().map(|i| &instances[*i]));
+        let geometry =
+            |mesh: &TriMesh, skin: Option<&[SkinnedVertex]>, instances: &Instances| match skin {
+                Some(vertices) => {
+                    let mut mesh = SkinnedMesh::new(context, vertices, instances);
+                    mesh.set_bones(bones);
+                    PropGeometry::Skinned(mesh)
+                }
+                None => PropGeometry::Static(InstancedMesh::new(context, instances, mesh)),
+            };
+        let (meshes, fading_meshes) = meshes
+            .iter()
+            .map(|(mesh, skin, material)| {
+                let fading_material = PhysicalMaterial {
+                    is_transparent: true,
+                    render_states: RenderStates {
+                        write_mask: WriteMask::COLOR,
+                        blend: Blend::TRANSPARENCY,
+                        ..RenderStates::default()
+                    },
+                    ..material.clone()
+                };
+                (
+                    RefCell::new(Gm::new(geometry(mesh, *skin, &opaque), material.clone())),
+                    RefCell::new(Gm::new(
+                        geometry(mesh, *skin, &Instances::default()),
+                        fading_material,
+                    )),
+                )
+            })
+            .unzip();
+        LodInstances {
+            meshes,
+            fading_meshes,
+            selected: RefCell::new(selected),
+            fading: RefCell::default(),
+        }
+    }
+
+    /// The meshes that have instances selected
+    fn drawn_meshes(&self) -> impl Iterator<Item = &PropMesh> {
+        let opaque = !self.selected.borrow().is_empty();
+        let fading = !self.fading.borrow().is_empty();
+        self.meshes
+            .iter()
+            .filter(move |_| opaque)
+            .chain(self.fading_meshes.iter().filter(move |_| fading))
+    }
+}
+
+impl InstancedProp {
+    /// Upload a prop model, `sky_clusters` are the clusters visible from the sky camera if the map has one
+    ///
+    /// Props with instances in the 3d skybox get a second set of instances for the sky pass,
+    /// so the selections of both passes stay uploaded between frames.
+    pub fn new(
+        context: &Context,
+        model: &PropModel,
+        materials: &[PhysicalMaterial],
+        sky_clusters: Option<&[bool]>,
+    ) -> Self {
+        let in_sky = sky_clusters.is_some_and(|clusters| {
+            !clusters.is_empty()
+                && model
+                    .instances
+                    .iter()
+                    .any(|instance| cluster_visible(clusters, instance.cluster))
+        });
+        let animator = model.animation.as_ref().map(|animation| PropAnimator {
+            animation: animation.clone(),
+            playing: true,
+            time: 0.0,
+            bones: animation.bone_matrices(0.0),
+        });
+        let bones = animator
+            .as_ref()
+            .map(|animator| animator.bones.as_slice())
+            .unwrap_or_default();
+
+        let mut model_bounds = AxisAlignedBoundingBox::EMPTY;
+        let lods = model
+            .lods
+            .iter()
+            .enumerate()
+            .map(|(level, lod)| {
+                let meshes: Vec<_> = lod
+                    .geometries
+                    .iter()
+                    .filter_map(|primitive| match &primitive.geometry {
+                        Geometry::Triangles(mesh) => Some((primitive, mesh)),
+                        _ => None,
+                    })
+                    .enumerate()
+                    .map(|(i, (primitive, mesh))| {
+                        if level == 0 {
+                            model_bounds.expand_with_aabb(&mesh.compute_aabb());
+                        }
+                        let material: PhysicalMaterial = primitive
+                            .material_index
+                            .and_then(|index| materials.get(index))
+                            .cloned()
+                            .unwrap_or_default();
+                        let skin = lod.skins.get(i).map(Vec::as_slice);
+                        (mesh, skin, material)
+                    })
+                    .collect();
+                // all instances start out at the most detailed level
+                let selected = if level == 0 {
+                    (0..model.instances.len()).collect()
+                } else {
+                    Vec::new()
+                };
+                InstancedLod {
+                    main: LodInstances::new(context, &meshes, &model.instances, selected, bones),
+                    sky: in_sky.then(|| {
+                        LodInstances::new(context, &meshes, &model.instances, Vec::new(), bones)
+                    }),
+                }
+            })
+            .collect();
+        let bounds = model
+            .instances
+            .iter()
+            .map(|instance| {
+                let mut bounds = model_bounds;
+                bounds.transform(&instance.transform);
+                bounds
+            })
+            .collect();
+
+        InstancedProp {
+            lods,
+            switch_points: model.lods.iter().map(|lod| lod.switch_point).collect(),
+            instances: model.instances.clone(),
+            bounds,
+            animator,
+        }
+    }
+
+    pub fn animator_mut(&mut self) -> Option<&mut PropAnimator> {
+        self.animator.as_mut()
+    }
+
+    /// Advance the animation by `elapsed` seconds and pose the meshes that have instances
+    ///
+    /// The vertices are skinned in the vertex shader so only the bone matrices change,
+    /// meshes without instances get the pose once they're selected.
+    pub fn animate(&mut self, elapsed: f32) {
+        let Some(animator) = self.animator.as_mut().filter(|animator| animator.playing) else {
+            return;
+        };
+        animator.time += elapsed;
+        animator.bones = animator.animation.bone_matrices(animator.time);
+        for mesh in self
+            .lods
+            .iter()
+            .flat_map(InstancedLod::passes)
+            .flat_map(LodInstances::drawn_meshes)
+        {
+            mesh.borrow_mut().geometry.set_bones(&animator.bones);
+        }
+    }
+
+    /// The level of detail to use at a lod metric
+    ///
+    /// The game's lod metric is 100 divided by the height in pixels of a sphere with a diameter of one hammer unit
+    pub fn lod(&self, metric: f32) -> usize {
+        self.switch_points
+            .iter()
+            // the shadow lod has a negative switch point and is never drawn
+            .rposition(|switch_point| *switch_point >= 0.0 && metric >= *switch_point)
+            .unwrap_or(0)
+    }
+
+    /// Upload the instances for a pass with the opacity and level of detail given by `view`, only touching the gpu when the selection changed
+    pub fn select(
+        &self,
+        pass: Pass,
+        view: impl Fn(&PropInstance, &AxisAlignedBoundingBox) -> (f32, usize),
+    ) {
+        if self.lods.first().and_then(|lod| lod.pass(pass)).is_none() {
+            return;
+        }
+        let mut selected = vec![Vec::new(); self.lods.len()];
+        let mut fading = vec![Vec::new(); self.lods.len()];
+        for (i, (instance, bounds)) in self.instances.iter().zip(&self.bounds).enumerate() {
+            let (opacity, lod) = view(instance, bounds);
+            let lod = lod.min(self.lods.len().saturating_sub(1));
+            match (opacity.clamp(0.0, 1.0) * 255.0) as u8 {
+                0 => {}
+                255 => selected[lod].push(i),
+                alpha => fading[lod].push((i, alpha)),
+            }
+        }
+
+        let bones = self
+            .animator
+            .as_ref()
+            .map(|animator| animator.bones.as_slice())
+            .unwrap_or_default();
+        for (lod, (selected, fading)) in self.lods.iter().zip(selected.into_iter().zip(fading)) {
+            let Some(lod) = lod.pass(pass) else {
+                continue;
+            };
+            if *lod.selected.borrow() != selected {
+                *lod.selected.borrow_mut() = selected;
+                let instances = self.opaque_instances(lod);
+                for mesh in &lod.meshes {
+                    let geometry = &mut mesh.borrow_mut().geometry;
+                    geometry.set_instances(&instances);
+                    geometry.set_bones(bones);
+                }
+            }
+            if *lod.fading.borrow() != fading {
+                *lod.fading.borrow_mut() = fading;
+                let instances = self.fading_instances(lod);
+                for mesh in &lod.fading_meshes {
+                    let geometry = &mut mesh.borrow_mut().geometry;
+                    geometry.set_instances(&instances);
+                    geometry.set_bones(bones);
+                }
+            }
+        }
+    }
+
+    /// Instance data for the opaque instances selected at a level of detail
+    fn opaque_instances(&self, lod: &LodInstances) -> Instances {
+        let selected = lod.selected.borrow();
+        instance_data(selected.iter().map(|i| &self.instances[*i]))
+    }
+
+    /// Instance data for the fading instances selected at a level of detail, with their opacity as color
+    fn fading_instances(&self, lod: &LodInstances) -> Instances {
+        let fading = lod.fading.borrow();
+        Instances {
+            colors: Some(
+                fading
+                    .iter()
+                    .map(|(_, alpha)| Srgba::new(255, 255, 255, *alpha))
+                    .collect(),
+            ),
+            ..instance_data(fading.iter().map(|(i, _)| &self.instances[*i]))
+        }
+    }
+
+    /// Number of instances currently selected for a pass
+    pub fn selected_count(&self, pass: Pass) -> usize {
+        self.lods
+            .iter()
+            .filter_map(|lod| lod.pass(pass))
+            .map(|lod| lod.selected.borrow().len() + lod.fading.borrow().len())
+            .sum()
+    }
+
+    /// The meshes that have instances selected for a pass
+    pub fn meshes(&self, pass: Pass) -> impl Iterator<Item = &PropMesh> {
+        (0..self.lods.len()).flat_map(move |lod| self.lod_meshes(lod, pass))
+    }
+
+    /// The meshes of a level of detail that have instances selected for a pass
+    pub fn lod_meshes(&self, lod: usize, pass: Pass) -> impl Iterator<Item = &PropMesh> {
+        self.lods
+            .get(lod)
+            .and_then(|lod| lod.pass(pass))
+            .into_iter()
+            .flat_map(LodInstances::drawn_meshes)
+    }
+}
+
+fn instance_data<'a>(instances: impl Iterator<Item = &'a PropInstance>) -> Instances {
+    Instances {
+        transformations: instances.map(|instance| instance.transform).collect(),
+        ..Instances::default()
+    }
+}
+
+pub struct PropData<'a> {
+    pub name: &'a str,
+    pub prop: &'a LoadedProp,
+    pub skin: i32,
+    pub body: i32,
+}
+
+/// A mesh of a level of detail, with the indices of its triangle vertices into the model's vertices
+struct LodMesh<'a> {
+    model_name: &'a str,
+    material: i32,
+    vertices: Vec<usize>,
+}
+
+/// The index of the model to use for every body part
+///
+/// The body value combines the choice for every part, with each part's index multiplied by the number of combinations of the parts before it
+pub fn body_models(part_sizes: impl IntoIterator<Item = usize>, body: i32) -> Vec<usize> {
+    let mut base = 1;
+    part_sizes
+        .into_iter()
+        .map(|count| {
+            let index = (body.max(0) as usize / base) % count.max(1);
+            base *= count.max(1);
+            index
+        })
+        .collect()
+}
+
+/// The body value selecting a model for every body part, the inverse of [`body_models`]
+pub fn body_value(part_sizes: impl IntoIterator<Item = usize>, models: &[usize]) -> i32 {
+    let mut base = 1;
+    part_sizes
+        .into_iter()
+        .zip(models)
+        .map(|(count, model)| {
+            let value = model * base;
+            base *= count.max(1);
+            value as i32
+        })
+        .sum()
+}
+
+#[test]
+fn test_body_models() {
+    assert_eq!(vec![0, 0, 0], body_models([2, 3, 1], 0));
+    assert_eq!(vec![1, 0, 0], body_models([2, 3, 1], 1));
+    assert_eq!(vec![0, 2, 0], body_models([2, 3, 1], 4));
+    assert_eq!(vec![1, 2, 0], body_models([2, 3, 1], 5));
+    assert_eq!(5, body_value([2, 3, 1], &[1, 2, 0]));
+}
+
+fn lod_meshes(prop: &LoadedProp, body: i32, lod: usize) -> impl Iterator<Item = LodMesh<'_>> {
+    let selection = body_models(
+        prop.mdl.body_parts.iter().map(|part| part.models.len()),
+        body,
+    );
+    prop.mdl
+        .body_parts
+        .iter()
+        .zip(&prop.vtx.body_parts)
+        .zip(selection)
+        .flat_map(|((mdl_part, vtx_part), model)| {
+            mdl_part.models.get(model).zip(vtx_part.models.get(model))
+        })
+        .flat_map(move |(mdl_model, vtx_model)| {
+            // models without the level use their least detailed one
+            let vtx_meshes = vtx_model
+                .lods
+                .get(lod)
+                .or(vtx_model.lods.last())
+                .map(|lod| lod.meshes.as_slice())
+                .unwrap_or_default();
+            mdl_model
+                .meshes
+                .iter()
+                .zip(vtx_meshes)
+                .map(move |(mdl_mesh, vtx_mesh)| {
+                    let offset = mdl_mesh.vertex_offset as usize + mdl_model.vertex_offset as usize;
+                    let vertices = vtx_mesh
+                        .strip_groups
+                        .iter()
+                        .flat_map(|group| {
+                            group.strips.iter().flat_map(move |strip| {
+                                strip.indices().map(move |index| {
+                                    let vertex = &group.vertices[group.indices[index] as usize];
+                                    vertex.original_mesh_vertex_id as usize + offset
+                                })
+                            })
+                        })
+                        .collect();
+                    LodMesh {
+                        model_name: mdl_model.name.as_str(),
+                        material: mdl_mesh.material,
+                        vertices,
+                    }
+                })
+        })
+}
+
+/// The vertices of every mesh of a level of detail with their bone weights, in the order of [`prop_to_meshes`]
+fn skinned_vertices(prop: &LoadedProp, body: i32, lod: usize) -> Vec<Vec<SkinnedVertex>> {
+    let (vertices, tangents) = (prop.vertices(), prop.tangents());
+    lod_meshes(prop, body, lod)
+        .map(|mesh| {
+            mesh.vertices
+                .iter()
+                .map(|index| {
+                    let vertex = &vertices[*index];
+                    let mut weights = [(0, 0.0); 3];
+                    for (weight, bone) in weights.iter_mut().zip(vertex.bone_weights.weights()) {
+                        *weight = (bone.bone_id, bone.weight);
+                    }
+                    SkinnedVertex {
+                        position: vertex.position.into(),
+                        normal: vertex.normal.into(),
+                        tangent: tangents[*index].into(),
+                        uv: vertex.texture_coordinates.into(),
+                        weights,
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn prop_to_meshes<'a>(
     prop: &'a PropData,
+    lod: usize,
     used_materials: &'a MaterialSet<'a>,
     show_textures: bool,
 ) -> impl Iterator<Item = Primitive> + 'a {
-    let transform = prop.transform;
-    let model = &prop.model;
+    let model = prop.prop;
+    let root_transform = model.root_transform();
 
-    let skin = match model.skin_tables().nth(prop.skin as usize) {
-        Some(skin) => skin,
-        None => {
+    let skin = match usize::try_from(prop.skin) {
+        Ok(skin) if skin < model.skin_count() => skin,
+        _ => {
             warn!(index = prop.skin, prop = prop.name, "invalid skin index");
-            model.skin_tables().next().unwrap()
+            0
         }
     };
 
-    model.meshes().map(move |mesh| {
+    lod_meshes(prop.prop, prop.body, lod).map(move |mesh| {
         let material_index = if show_textures {
-            skin.texture_info(mesh.material_index())
+            model
+                .skin_texture(skin, mesh.material)
                 .map(|mat| used_materials.get_index(mat))
         } else {
             None
         };
 
-        let positions: Vec<Vec3> = mesh
-            .vertices()
-            .map(|vertex| model.apply_root_transform(vertex.position))
+        let vertices = || mesh.vertices.iter().map(|index| &model.vertices()[*index]);
+        let positions: Vec<Vec3> = vertices()
+            .map(|vertex| root_transform.transform_vector(vertex.position.into()))
             .map(map_coords)
             .collect();
-        let normals: Vec<Vec3> = mesh
-            .vertices()
-            .map(|vertex| map_coords(vertex.normal))
-            .collect();
-        let uvs: Vec<Vec2> = mesh
-            .vertices()
+        let normals: Vec<Vec3> = vertices().map(|vertex| map_coords(vertex.normal)).collect();
+        let uvs: Vec<Vec2> = vertices()
             .map(|vertex| vertex.texture_coordinates.into())
             .collect();
 
-        let tangents: Vec<Vec4> = mesh.tangents().map(|tangent| tangent.into()).collect();
+        let tangents: Vec<Vec4> = mesh
+            .vertices
+            .iter()
+            .map(|index| model.tangents()[*index].into())
+            .collect();
 
         let geometry = Geometry::Triangles(TriMesh {
             positions: Positions::F32(positions),
@@ -127,7 +1068,7 @@ fn prop_to_meshes<'a>(
 
         Primitive {
             name: mesh.model_name.into(),
-            transformation: transform,
+            transformation: Mat4::from_scale(1.0),
             animations: vec![],
             geometry,
             material_index,
@@ -135,6 +1076,6 @@ fn prop_to_meshes<'a>(
     })
 }
 
-fn prop_texture_to_material(texture: &str, loader: &Loader) -> CpuMaterial {
-    convert_material(load_material_fallback(texture, loader))
+pub fn prop_texture_to_material(texture: &str, loader: &Loader, assets: &AssetLog) -> CpuMaterial {
+    convert_material(load_material_fallback(texture, loader, assets))
 }
@@ -1,30 +1,104 @@
+use crate::bsp::UNIT_SCALE;
 use crate::control::{Control, DebugToggle};
+use crate::fog::{Fog, FogSlice};
+use crate::light::{ConeLight, EnvironmentLight, MapLight};
+use crate::lightmap::{LightmapMaterial, LightmappedMesh};
+use crate::player::{DemoPlayers, EntityMesh};
+use crate::projectile::DemoObjects;
+use crate::prop::{InstancedProp, Pass, PropMesh};
+use crate::sky::SkyCamera;
 use crate::ui::DebugType;
+use crate::vis::{cluster_visible, Visibility};
 use crate::DebugUI;
 use three_d::*;
+use three_d_asset::ProjectionType;
+
+/// Largest ratio between the far and near plane of one depth slice, that of the original fixed 0.1 to 45 projection
+///
+/// three-d builds the projection and picks the depth test of its materials itself, so neither reversed-z nor a
+/// logarithmic depth can be used. Instead the view distance is split into slices that are rendered from the far one
+/// to the near one, with the depth cleared in between.
+const MAX_DEPTH_RATIO: f32 = 450.0;
+/// Distance of the near plane, about 19 hammer units
+const MIN_NEAR: f32 = 0.1;
+/// How far the slices reach into the one behind them, to not leave gaps where they meet
+const SLICE_OVERLAP: f32 = 1.01;
+
+/// The near and far plane of each depth slice, from the far one to the near one
+fn depth_ranges(view_distance: f32) -> Vec<(f32, f32)> {
+    let mut ranges = Vec::new();
+    let mut far = view_distance;
+    loop {
+        let near = (far / MAX_DEPTH_RATIO).max(MIN_NEAR);
+        ranges.push((near, far));
+        if near <= MIN_NEAR {
+            return ranges;
+        }
+        far = near * SLICE_OVERLAP;
+    }
+}
+
+/// Colors for the levels of detail in the lod debug view
+const LOD_COLORS: [Srgba; 8] = [
+    Srgba::GREEN,
+    Srgba::new_opaque(128, 255, 0),
+    Srgba::new_opaque(255, 255, 0),
+    Srgba::new_opaque(255, 128, 0),
+    Srgba::RED,
+    Srgba::new_opaque(255, 0, 255),
+    Srgba::BLUE,
+    Srgba::WHITE,
+];
+
+/// Number of objects and prop instances drawn and skipped by frustum culling in the last frame
+#[derive(Default, Debug, Clone, Copy)]
+pub struct CullStats {
+    pub drawn: usize,
+    pub culled: usize,
+}
 
 pub struct Renderer<C: Control> {
     gui: DebugUI,
     pub models: Vec<Model<PhysicalMaterial>>,
+    pub lightmapped_world: Vec<Gm<LightmappedMesh, LightmapMaterial>>,
+    pub props: Vec<InstancedProp>,
+    /// Screen width in pixels where all props start and finish fading
+    pub prop_screen_fade: Option<(f32, f32)>,
+    /// Players from the demo being played back
+    pub players: Option<DemoPlayers>,
+    /// Projectiles and buildings from the demo being played back
+    pub demo_objects: Option<DemoObjects>,
+    pub visibility: Option<Visibility>,
+    pub skybox: Option<Skybox>,
+    pub sky_camera: Option<SkyCamera>,
+    pub fog: Option<Fog>,
+    /// Textures the scene is rendered into before applying the fog, reused while the viewport size is unchanged
+    fog_targets: Option<(Texture2D, DepthTexture2D)>,
     ambient_lights: Vec<AmbientLight>,
     directional_lights: Vec<DirectionalLight>,
+    point_lights: Vec<PointLight>,
+    spot_lights: Vec<ConeLight>,
+    /// Base intensity of the directional and ambient lights, scaled by the ui sliders
+    light_intensity: (f32, f32),
     pub context: Context,
     control: C,
     debug_toggle: DebugToggle,
+    cull_stats: CullStats,
     pub camera: Camera,
 }
 
 impl<C: Control> Renderer<C> {
     pub fn new(window: &Window, control: C) -> Self {
         let context = window.gl();
+        let gui = DebugUI::new(&context);
         let camera = Camera::new_perspective(
             window.viewport(),
             vec3(9.0, 4.0, 5.0),
             vec3(0.0, 0.0, 0.0),
             vec3(0.0, 1.0, 0.0),
-            degrees(60.0),
-            0.1,
-            45.0,
+            degrees(gui.fov),
+            MIN_NEAR,
+            gui.view_distance,
         );
 
         let ambient_lights = vec![AmbientLight {
@@ -40,36 +114,76 @@ impl<C: Control> Renderer<C> {
 
         Self {
             models: Vec::new(),
-            gui: DebugUI::new(&context),
+            lightmapped_world: Vec::new(),
+            props: Vec::new(),
+            prop_screen_fade: None,
+            players: None,
+            demo_objects: None,
+            visibility: None,
+            skybox: None,
+            sky_camera: None,
+            fog: None,
+            fog_targets: None,
+            gui,
             ambient_lights,
             directional_lights,
+            point_lights: Vec::new(),
+            spot_lights: Vec::new(),
+            light_intensity: (1.0, 1.0),
             context,
             control,
             debug_toggle: DebugToggle::new(),
+            cull_stats: CullStats::default(),
             camera,
         }
     }
 
     pub fn render(&mut self, mut frame_input: FrameInput) -> FrameOutput {
-        let (ui_change, _panel_width) =
-            self.gui
-                .update(&mut frame_input, &self.camera, &mut self.control);
+        let cluster = self.visibility.as_ref().and_then(Visibility::cluster);
+        let animations = self
+            .props
+            .iter_mut()
+            .filter_map(InstancedProp::animator_mut)
+            .collect();
+        let (ui_change, _panel_width) = self.gui.update(
+            &mut frame_input,
+            &self.camera,
+            cluster,
+            self.cull_stats,
+            animations,
+            &mut self.control,
+        );
+        for prop in self.props.iter_mut() {
+            prop.animate(frame_input.elapsed_time as f32 / 1000.0);
+        }
         let change = frame_input.first_frame || ui_change;
         if change {
+            let (directional_intensity, ambient_intensity) = self.light_intensity;
+            // all props cast shadows, not only the ones visible from the camera
+            let mut shadow_casters: Vec<&dyn Geometry> = Vec::new();
             if self.gui.shadows_enabled {
-                self.directional_lights[0]
-                    .generate_shadow_map(1024, self.models.iter().flat_map(|model| model.iter()));
-                self.directional_lights[1]
-                    .generate_shadow_map(1024, self.models.iter().flat_map(|model| model.iter()));
-            } else {
-                self.directional_lights[0].clear_shadow_map();
-                self.directional_lights[1].clear_shadow_map();
+                for prop in &self.props {
+                    prop.select(Pass::Main, |_, _| (1.0, 0));
+                }
+                let models = self.models.iter().flat_map(|model| model.iter());
+                let props = self.props.iter().flat_map(|prop| prop.meshes(Pass::Main));
+                shadow_casters.extend(models.map(|gm| &gm.geometry as &dyn Geometry));
+                shadow_casters.extend(props.map(|gm| gm as &dyn Geometry));
+            }
+            for light in self.directional_lights.iter_mut() {
+                if self.gui.shadows_enabled {
+                    light.generate_shadow_map(1024, shadow_casters.iter().copied());
+                } else {
+                    light.clear_shadow_map();
+                }
+                light.intensity = directional_intensity * self.gui.directional_intensity;
             }
-            self.directional_lights[0].intensity = self.gui.directional_intensity;
-            self.directional_lights[1].intensity = self.gui.directional_intensity;
-            self.ambient_lights[0].intensity = self.gui.ambient_intensity;
-            self.camera
-                .set_perspective_projection(degrees(self.gui.fov), 0.1, 45.0);
+            self.ambient_lights[0].intensity = ambient_intensity * self.gui.ambient_intensity;
+            self.camera.set_perspective_projection(
+                degrees(self.gui.fov),
+                MIN_NEAR,
+                self.gui.view_distance,
+            );
         }
 
         let viewport = Viewport {
@@ -92,34 +206,62 @@ impl<C: Control> Renderer<C> {
             frame_input.accumulated_time,
         );
 
-        let lights = &[
-            &self.ambient_lights[0] as &dyn Light,
-            &self.directional_lights[0],
-            &self.directional_lights[1],
-        ];
+        if let Some(visibility) = self.visibility.as_mut() {
+            visibility.update(*self.camera.position());
+        }
+        if let Some(playback) = self.control.playback() {
+            if let Some(players) = self.players.as_mut() {
+                players.update(playback);
+            }
+            if let Some(objects) = self.demo_objects.as_mut() {
+                objects.update(playback.tick);
+            }
+        }
+
+        // taken out of self while the lights borrow it
+        let mut fog_targets = self.fog_targets.take();
+        let mut lights: Vec<&dyn Light> = self
+            .ambient_lights
+            .iter()
+            .map(|light| light as &dyn Light)
+            .chain(
+                self.directional_lights
+                    .iter()
+                    .map(|light| light as &dyn Light),
+            )
+            .collect();
+        if self.gui.dynamic_lights {
+            lights.extend(self.nearest_lights(self.gui.max_lights));
+        }
+        let lights = lights.as_slice();
 
         // Light pass
         let target = frame_input.screen();
         target.clear(ClearState::default());
 
-        let geometries = self
+        let mut cull_stats = CullStats::default();
+        let clusters = self.visible_clusters();
+        if !matches!(self.gui.debug_type, DebugType::None | DebugType::Lod) {
+            self.select_props(Pass::Main, &self.camera, clusters, false);
+        }
+        let geometries: Vec<&dyn Geometry> = self
             .models
             .iter()
+            .filter(|_| self.gui.show_bsp)
             .enumerate()
-            .filter_map(|(i, model)| {
-                if (!self.gui.show_bsp && i == 0) || (!self.gui.show_props && i > 0) {
-                    None
-                } else {
-                    Some(model)
-                }
-            })
-            .flat_map(|model| model.iter());
+            .flat_map(|(i, model)| model.iter().enumerate().map(move |(j, gm)| (i, j, gm)))
+            .filter(|(i, j, _)| self.is_visible(clusters, *i, *j))
+            .map(|(_, _, gm)| &gm.geometry as &dyn Geometry)
+            .chain(self.visible_props(Pass::Main).map(|gm| gm as &dyn Geometry))
+            .chain(self.demo_meshes().map(|gm| &gm.geometry as &dyn Geometry))
+            .collect();
+        let geometries = geometries.iter().copied();
 
         match self.gui.debug_type {
             DebugType::Normal => target.render_with_material(
                 &NormalMaterial::default(),
                 &self.camera,
-                geometries.map(|gm| &gm.geometry),
+                geometries,
                 lights,
             ),
             DebugType::Depth => {
@@ -132,39 +274,437 @@ impl<C: Control> Renderer<C> {
             DebugType::Orm => target.render_with_material(
                 &ORMMaterial::default(),
                 &self.camera,
-                geometries.map(|gm| &gm.geometry),
+                geometries,
                 lights,
             ),
             DebugType::Position => {
                 let position_material = PositionMaterial::default();
-                target.render_with_material(
-                    &position_material,
-                    &self.camera,
-                    geometries.map(|gm| &gm.geometry),
-                    lights,
-                )
+                target.render_with_material(&position_material, &self.camera, geometries, lights)
             }
             DebugType::Uv => {
                 let uv_material = UVMaterial::default();
-                target.render_with_material(
-                    &uv_material,
-                    &self.camera,
-                    geometries.map(|gm| &gm.geometry),
-                    lights,
-                )
+                target.render_with_material(&uv_material, &self.camera, geometries, lights)
             }
             DebugType::Color => target.render_with_material(
                 &ColorMaterial::default(),
                 &self.camera,
-                geometries.map(|gm| &gm.geometry),
+                geometries,
                 lights,
             ),
-            DebugType::None => target.render(&self.camera, geometries, lights),
+            DebugType::Lod => {
+                cull_stats = self.render_scene(&target, &self.camera, lights);
+                &target
+            }
+            DebugType::None => match &self.fog {
+                Some(fog) if self.gui.fog => {
+                    // the fog is applied afterwards from the depth, together with the tone mapping
+                    let mut camera = self.camera.clone();
+                    camera.disable_tone_and_color_mapping();
+                    let (mut color, mut depth) = match fog_targets.take() {
+                        Some((color, depth))
+                            if color.width() == viewport.width
+                                && color.height() == viewport.height =>
+                        {
+                            (color, depth)
+                        }
+                        _ => scene_textures(&self.context, viewport),
+                    };
+                    let (objects, stats) = self.scene_objects(&camera);
+                    cull_stats = stats;
+                    // each slice is fogged with its own projection before the depth is cleared for the next one
+                    let slices = self.depth_slices(&camera);
+                    let fog_slices = self.depth_slices(&self.camera);
+                    for (i, (slice, fog_slice)) in slices.iter().zip(&fog_slices).enumerate() {
+                        {
+                            let scene = RenderTarget::new(
+                                color.as_color_target(None),
+                                depth.as_depth_target(),
+                            );
+                            if i == 0 {
+                                scene.clear(ClearState::default());
+                                if self.gui.show_sky {
+                                    self.render_sky(&scene, &camera, lights);
+                                }
+                            } else {
+                                scene.clear(ClearState::depth(1.0));
+                            }
+                            self.draw_objects(Pass::Main, &scene, slice, &objects, lights);
+                        }
+
+                        target.apply_screen_effect(
+                            &FogSlice {
+                                fog,
+                                overlay: i > 0,
+                            },
+                            fog_slice,
+                            &[],
+                            Some(ColorTexture::Single(&color)),
+                            Some(DepthTexture::Single(&depth)),
+                        );
+                    }
+                    fog_targets = Some((color, depth));
+                    &target
+                }
+                _ => {
+                    cull_stats = self.render_scene(&target, &self.camera, lights);
+                    &target
+                }
+            },
         };
+        self.cull_stats = cull_stats;
+        self.fog_targets = fog_targets;
 
         if self.debug_toggle.enabled {
             target.write(|| self.gui.render());
         }
         FrameOutput::default()
     }
+
+    /// Set the view distance to cover the whole map
+    pub fn set_bounds(&mut self, bounds: AxisAlignedBoundingBox) {
+        let size = bounds.size().magnitude().max(1.0);
+        self.gui.view_distance = size;
+        self.gui.max_view_distance = size * 2.0;
+    }
+
+    /// Replace the default lights with the sun and ambient light of the map
+    pub fn set_environment_light(&mut self, light: EnvironmentLight) {
+        self.directional_lights = vec![DirectionalLight::new(
+            &self.context,
+            light.intensity,
+            light.color,
+            &light.direction,
+        )];
+        self.ambient_lights = vec![AmbientLight {
+            color: light.ambient_color,
+            intensity: light.ambient_intensity,
+            ..Default::default()
+        }];
+        self.light_intensity = (light.intensity, light.ambient_intensity);
+        // the map's ambient light is used as is instead of the default dimmed white light
+        self.gui.ambient_intensity = 1.0;
+    }
+
+    pub fn set_map_lights(&mut self, lights: &[MapLight]) {
+        for light in lights {
+            match light.spot {
+                Some(cone) => self.spot_lights.push(ConeLight {
+                    intensity: light.intensity,
+                    color: light.color,
+                    position: light.position,
+                    cone,
+                    attenuation: light.attenuation,
+                }),
+                None => self.point_lights.push(PointLight::new(
+                    &self.context,
+                    light.intensity,
+                    light.color,
+                    &light.position,
+                    light.attenuation,
+                )),
+            }
+        }
+    }
+
+    /// The point and spot lights closest to the camera
+    ///
+    /// Every light is a separate shader uniform, so only a limited number can be used at once
+    fn nearest_lights(&self, count: usize) -> impl Iterator<Item = &dyn Light> {
+        let camera = *self.camera.position();
+        let mut lights: Vec<(f32, bool, &dyn Light)> = self
+            .point_lights
+            .iter()
+            .map(|light| (light.position.distance2(camera), false, light as &dyn Light))
+            .chain(
+                self.spot_lights
+                    .iter()
+                    .map(|light| (light.position.distance2(camera), true, light as &dyn Light)),
+            )
+            .collect();
+        lights.sort_by(|a, b| a.0.total_cmp(&b.0));
+        lights.truncate(count);
+        // the shader is compiled for the order of the light types, so keep all point lights before the spot lights
+        // to only need a shader for every number of spot lights instead of every ordering
+        lights.sort_by_key(|(_, spot, _)| *spot);
+        lights.into_iter().map(|(_, _, light)| light)
+    }
+
+    /// Render the sky, world and props with their own materials
+    fn render_scene(
+        &self,
+        target: &RenderTarget,
+        camera: &Camera,
+        lights: &[&dyn Light],
+    ) -> CullStats {
+        if self.gui.show_sky {
+            self.render_sky(target, camera, lights);
+        }
+        let (objects, stats) = self.scene_objects(camera);
+        for (i, slice) in self.depth_slices(camera).iter().enumerate() {
+            if i > 0 {
+                target.clear(ClearState::depth(1.0));
+            }
+            self.draw_objects(Pass::Main, target, slice, &objects, lights);
+        }
+        stats
+    }
+
+    /// The camera with the projection of each depth slice, from the far one to the near one
+    fn depth_slices(&self, camera: &Camera) -> Vec<Camera> {
+        depth_ranges(self.gui.view_distance)
+            .into_iter()
+            .map(|(near, far)| {
+                let mut slice = camera.clone();
+                slice.set_perspective_projection(degrees(self.gui.fov), near, far);
+                slice
+            })
+            .collect()
+    }
+
+    /// The world, props and demo entities to draw from the camera
+    fn scene_objects(&self, camera: &Camera) -> (Vec<&dyn Object>, CullStats) {
+        let (mut objects, stats) = self.cull_objects(Pass::Main, camera, self.visible_clusters());
+        objects.extend(self.demo_meshes().map(|gm| gm as &dyn Object));
+        (objects, stats)
+    }
+
+    /// The players, projectiles and buildings from the demo
+    fn demo_meshes(&self) -> impl Iterator<Item = &EntityMesh> {
+        let players = self.players.iter().flat_map(DemoPlayers::meshes);
+        players.chain(self.demo_objects.iter().flat_map(DemoObjects::meshes))
+    }
+
+    /// Render the world and props with their own materials, limited to the visible clusters if set
+    fn render_objects(
+        &self,
+        pass: Pass,
+        target: &RenderTarget,
+        camera: &Camera,
+        lights: &[&dyn Light],
+        clusters: Option<&[bool]>,
+    ) -> CullStats {
+        let (objects, stats) = self.cull_objects(pass, camera, clusters);
+        self.draw_objects(pass, target, camera, &objects, lights);
+        stats
+    }
+
+    /// The world and the selected props in the visible clusters, and in the frustum when culling is enabled
+    ///
+    /// In the lod debug view the props are left out, they are drawn by `draw_objects` in the color of their lod.
+    fn cull_objects(
+        &self,
+        pass: Pass,
+        camera: &Camera,
+        clusters: Option<&[bool]>,
+    ) -> (Vec<&dyn Object>, CullStats) {
+        let baked = self.gui.baked_lighting && !self.lightmapped_world.is_empty();
+        let world = self
+            .lightmapped_world
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| baked && self.gui.show_bsp && self.is_visible(clusters, 0, *j))
+            .map(|(_, gm)| gm as &dyn Object);
+        let models = self
+            .models
+            .iter()
+            .enumerate()
+            .filter(|_| self.gui.show_bsp && !baked)
+            .flat_map(|(i, model)| model.iter().enumerate().map(move |(j, gm)| (i, j, gm)))
+            .filter(|(i, j, _)| self.is_visible(clusters, *i, *j))
+            .map(|(_, _, gm)| gm as &dyn Object);
+        let (mut objects, culled): (Vec<_>, Vec<_>) = world
+            .chain(models)
+            .partition(|object| !self.gui.frustum_culling || camera.in_frustum(&object.aabb()));
+        let mut stats = self.select_props(pass, camera, clusters, self.gui.frustum_culling);
+        stats.drawn += objects.len();
+        stats.culled += culled.len();
+        if self.gui.debug_type != DebugType::Lod {
+            objects.extend(self.visible_props(pass).map(|gm| gm as &dyn Object));
+        }
+        (objects, stats)
+    }
+
+    fn draw_objects(
+        &self,
+        pass: Pass,
+        target: &RenderTarget,
+        camera: &Camera,
+        objects: &[&dyn Object],
+        lights: &[&dyn Light],
+    ) {
+        target.render(camera, objects, lights);
+        if self.gui.debug_type == DebugType::Lod {
+            self.render_prop_lods(pass, target, camera, lights);
+        }
+    }
+
+    /// Upload the prop instances that are in the visible clusters, and optionally in the frustum
+    ///
+    /// Props that are fading out by distance or screen size get uploaded with their opacity
+    fn select_props(
+        &self,
+        pass: Pass,
+        camera: &Camera,
+        clusters: Option<&[bool]>,
+        frustum: bool,
+    ) -> CullStats {
+        let mut stats = CullStats::default();
+        if !self.gui.show_props {
+            return stats;
+        }
+        // pixels covered by one viewer unit at a distance of one viewer unit
+        let pixel_scale = match camera.projection_type() {
+            ProjectionType::Perspective { field_of_view_y } => {
+                camera.viewport().height as f32 / (2.0 * (*field_of_view_y / 2.0).tan())
+            }
+            _ => f32::INFINITY,
+        };
+        let culled = std::cell::Cell::new(0);
+        for prop in &self.props {
+            prop.select(pass, |instance, bounds| {
+                if !clusters.is_none_or(|clusters| cluster_visible(clusters, instance.cluster)) {
+                    return (0.0, 0);
+                }
+                if frustum && !camera.in_frustum(bounds) {
+                    culled.set(culled.get() + 1);
+                    return (0.0, 0);
+                }
+                let distance = instance
+                    .transform
+                    .w
+                    .truncate()
+                    .distance(*camera.position())
+                    .max(MIN_NEAR);
+                let lod = if self.gui.prop_lod {
+                    // the game's lod metric, based on the screen height of a one hammer unit sphere
+                    prop.lod(100.0 * distance / (UNIT_SCALE * pixel_scale))
+                } else {
+                    0
+                };
+                if !self.gui.prop_fade {
+                    return (1.0, lod);
+                }
+                let screen_width = bounds.size().magnitude() * pixel_scale / distance;
+                let opacity = instance
+                    .fade
+                    .opacity(distance, screen_width, self.prop_screen_fade);
+                (opacity, lod)
+            });
+            stats.drawn += prop.selected_count(pass);
+        }
+        stats.culled = culled.get();
+        stats
+    }
+
+    /// Render the props colored by their level of detail
+    fn render_prop_lods(
+        &self,
+        pass: Pass,
+        target: &RenderTarget,
+        camera: &Camera,
+        lights: &[&dyn Light],
+    ) {
+        if !self.gui.show_props {
+            return;
+        }
+        for (lod, color) in LOD_COLORS.into_iter().enumerate() {
+            let material = ColorMaterial {
+                color,
+                ..ColorMaterial::default()
+            };
+            let meshes = self
+                .props
+                .iter()
+                .flat_map(|prop| prop.lod_meshes(lod, pass));
+            target.render_with_material(&material, camera, meshes, lights);
+        }
+    }
+
+    /// Meshes of the props with selected instances
+    fn visible_props(&self, pass: Pass) -> impl Iterator<Item = &PropMesh> {
+        self.props
+            .iter()
+            .filter(move |prop| self.gui.show_props && prop.selected_count(pass) > 0)
+            .flat_map(move |prop| prop.meshes(pass))
+    }
+
+    /// Render the 2d skybox and the 3d skybox area behind the world
+    fn render_sky(&self, target: &RenderTarget, camera: &Camera, lights: &[&dyn Light]) {
+        if let Some(skybox) = &self.skybox {
+            target.render(camera, skybox, &[]);
+        }
+        // an empty set would count every cluster as visible, the sky camera doesn't see anything then
+        let sky_camera = self
+            .sky_camera
+            .as_ref()
+            .filter(|sky_camera| !sky_camera.visible_clusters.is_empty());
+        if let Some(sky_camera) = sky_camera {
+            let mut sky = camera.clone();
+            let position = sky_camera.origin + camera.position() / sky_camera.scale;
+            sky.set_view(position, position + camera.view_direction(), *camera.up());
+            self.render_objects(
+                Pass::Sky,
+                target,
+                &sky,
+                lights,
+                Some(&sky_camera.visible_clusters),
+            );
+            target.clear(ClearState::depth(1.0));
+        }
+    }
+
+    /// Clusters visible from the camera, `None` when visibility culling is disabled
+    fn visible_clusters(&self) -> Option<&[bool]> {
+        match &self.visibility {
+            Some(visibility) if self.gui.pvs_culling => Some(visibility.camera_clusters()),
+            _ => None,
+        }
+    }
+
+    fn is_visible(&self, clusters: Option<&[bool]>, model: usize, primitive: usize) -> bool {
+        match (&self.visibility, clusters) {
+            (Some(visibility), Some(clusters)) => {
+                visibility.is_visible_from(clusters, model, primitive)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Color and depth textures to render the scene into, for applying screen effects afterwards
+fn scene_textures(context: &Context, viewport: Viewport) -> (Texture2D, DepthTexture2D) {
+    let color = Texture2D::new_empty::<[f32; 4]>(
+        context,
+        viewport.width,
+        viewport.height,
+        Interpolation::Nearest,
+        Interpolation::Nearest,
+        None,
+        Wrapping::ClampToEdge,
+        Wrapping::ClampToEdge,
+    );
+    let depth = DepthTexture2D::new::<f32>(
+        context,
+        viewport.width,
+        viewport.height,
+        Wrapping::ClampToEdge,
+        Wrapping::ClampToEdge,
+    );
+    (color, depth)
+}
+
+#[test]
+fn test_depth_ranges() {
+    assert_eq!(vec![(0.1, 45.0)], depth_ranges(45.0));
+    assert_eq!(vec![(0.1, 10.0)], depth_ranges(10.0));
+
+    // a large map is split into more slices instead of moving the near plane out
+    let ranges = depth_ranges(400.0);
+    assert_eq!(2, ranges.len());
+    assert_eq!(400.0, ranges[0].1);
+    assert_eq!(MIN_NEAR, ranges.last().unwrap().0);
+    for (near, far) in &ranges {
+        assert!(far / near <= MAX_DEPTH_RATIO + 0.001);
+    }
+    assert!(ranges[1].1 > ranges[0].0);
 }
@@ -0,0 +1,39 @@
+uniform mat4 viewProjectionInverse;
+uniform vec4 fogColor;
+uniform float fogStart;
+uniform float fogEnd;
+uniform float fogMaxDensity;
+uniform vec3 eyePosition;
+uniform int overlay;
+
+in vec2 uvs;
+
+layout (location = 0) out vec4 outColor;
+
+vec3 world_pos_from_depth(float depth, vec2 uv) {
+    vec4 clipSpacePosition = vec4(uv * 2.0 - 1.0, depth * 2.0 - 1.0, 1.0);
+    vec4 position = viewProjectionInverse * clipSpacePosition;
+    return position.xyz / position.w;
+}
+
+void main()
+{
+    vec4 color = sample_color(uvs);
+    float depth = sample_depth(uvs);
+    if (overlay != 0 && depth >= 1.0) {
+        discard;
+    }
+    vec3 pos = world_pos_from_depth(depth, uvs);
+
+    // the sky is drawn without depth and isn't fogged
+    float factor = 0.0;
+    if (depth < 1.0) {
+        float dist = distance(pos, eyePosition);
+        factor = clamp((dist - fogStart) / max(fogEnd - fogStart, 0.0001), 0.0, fogMaxDensity);
+    }
+
+    outColor = mix(color, fogColor, factor);
+    outColor.rgb = tone_mapping(outColor.rgb);
+    outColor.rgb = color_mapping(outColor.rgb);
+    gl_FragDepth = depth;
+}
@@ -0,0 +1,26 @@
+uniform vec4 surfaceColor;
+uniform sampler2D lightmap;
+
+#ifdef USE_TEXTURE
+uniform sampler2D tex;
+uniform mat3 textureTransformation;
+#endif
+
+in vec2 lightmap_uvs;
+in vec4 col;
+
+layout (location = 0) out vec4 outColor;
+
+void main()
+{
+    outColor = surfaceColor * col;
+
+    #ifdef USE_TEXTURE
+    outColor *= texture(tex, (textureTransformation * vec3(uvs, 1.0)).xy);
+    #endif
+
+    outColor.rgb *= texture(lightmap, lightmap_uvs).rgb;
+
+    outColor.rgb = tone_mapping(outColor.rgb);
+    outColor.rgb = color_mapping(outColor.rgb);
+}
@@ -0,0 +1,46 @@
+uniform mat4 viewProjection;
+uniform mat4 modelMatrix;
+in vec3 position;
+in vec2 lightmap_coordinates;
+
+out vec3 pos;
+out vec2 lightmap_uvs;
+
+#ifdef USE_NORMALS
+uniform mat4 normalMatrix;
+in vec3 normal;
+out vec3 nor;
+#endif
+
+#ifdef USE_UVS
+in vec2 uv_coordinates;
+out vec2 uvs;
+#endif
+
+#ifdef USE_VERTEX_COLORS
+in vec4 color;
+#endif
+
+out vec4 col;
+
+void main()
+{
+    vec4 worldPosition = modelMatrix * vec4(position, 1.);
+    worldPosition /= worldPosition.w;
+    gl_Position = viewProjection * worldPosition;
+    pos = worldPosition.xyz;
+    lightmap_uvs = lightmap_coordinates;
+
+#ifdef USE_NORMALS
+    nor = normalize(mat3(normalMatrix) * normal);
+#endif
+
+#ifdef USE_UVS
+    uvs = uv_coordinates;
+#endif
+
+    col = vec4(1.0);
+#ifdef USE_VERTEX_COLORS
+    col *= color;
+#endif
+}
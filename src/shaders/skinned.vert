@@ -0,0 +1,73 @@
+uniform mat4 viewProjection;
+uniform mat4 bones[MAX_BONES];
+in vec3 position;
+in vec3 bone_ids;
+in vec3 bone_weights;
+
+in vec4 row1;
+in vec4 row2;
+in vec4 row3;
+
+out vec3 pos;
+
+#ifdef USE_NORMALS
+in vec3 normal;
+out vec3 nor;
+
+#ifdef USE_TANGENTS
+in vec4 tangent;
+out vec3 tang;
+out vec3 bitang;
+#endif
+
+#endif
+
+#ifdef USE_UVS
+in vec2 uv_coordinates;
+out vec2 uvs;
+#endif
+
+#ifdef USE_INSTANCE_COLORS
+in vec4 instance_color;
+#endif
+
+out vec4 col;
+
+void main()
+{
+    mat4 skin = bones[int(bone_ids.x)] * bone_weights.x
+        + bones[int(bone_ids.y)] * bone_weights.y
+        + bones[int(bone_ids.z)] * bone_weights.z;
+
+    mat4 transform;
+    transform[0] = vec4(row1.x, row2.x, row3.x, 0.0);
+    transform[1] = vec4(row1.y, row2.y, row3.y, 0.0);
+    transform[2] = vec4(row1.z, row2.z, row3.z, 0.0);
+    transform[3] = vec4(row1.w, row2.w, row3.w, 1.0);
+    mat4 local2World = transform * skin;
+
+    vec4 worldPosition = local2World * vec4(position, 1.);
+    worldPosition /= worldPosition.w;
+    gl_Position = viewProjection * worldPosition;
+    pos = worldPosition.xyz;
+
+#ifdef USE_NORMALS
+    mat3 normalMat = mat3(transpose(inverse(local2World)));
+    nor = normalize(normalMat * normal);
+
+#ifdef USE_TANGENTS
+    tang = normalize(normalMat * tangent.xyz);
+    bitang = normalize(cross(nor, tang) * tangent.w);
+#endif
+
+#endif
+
+#ifdef USE_UVS
+    uvs = uv_coordinates;
+#endif
+
+    col = vec4(1.0);
+#ifdef USE_INSTANCE_COLORS
+    col *= instance_color;
+#endif
+}
@@ -0,0 +1,106 @@
+use crate::bsp::map_coords;
+use crate::loader::Loader;
+use crate::material::{convert_texture, load_material, AssetLog, TextureData};
+use crate::Error;
+use image::imageops::FilterType;
+use three_d::{CpuTexture, Vec3};
+use tracing::error;
+use vbsp::Bsp;
+use vbsp_entities_tf2::Entity;
+
+/// Skybox face suffixes, in the order of the cubemap sides: +X, -X, +Y, -Y, +Z, -Z
+///
+/// [`map_coords`] maps Source's +Y to +X and +X to +Z, so Source's left (`lf`, +Y) face is three-d's +X
+/// face and its front (`ft`, +X) face is +Z
+const FACE_SUFFIXES: [&str; 6] = ["lf", "rt", "up", "dn", "ft", "bk"];
+
+#[derive(Default)]
+pub struct Sky {
+    /// Cubemap faces of the 2d skybox
+    pub faces: Option<[CpuTexture; 6]>,
+    pub camera: Option<SkyCamera>,
+}
+
+/// Position and scale of the 3d skybox
+pub struct SkyCamera {
+    pub origin: Vec3,
+    pub scale: f32,
+    /// Clusters visible from the sky camera, the 3d skybox is only drawn when they are known
+    pub visible_clusters: Vec<bool>,
+}
+
+pub fn load_sky(bsp: &Bsp, loader: &Loader, assets: &AssetLog) -> Sky {
+    let entities = || bsp.entities.iter().flat_map(|ent| ent.parse::<Entity>());
+
+    let faces = entities()
+        .find_map(|ent| match ent {
+            Entity::Worldspawn(world) => Some(world.skyname),
+            _ => None,
+        })
+        .and_then(|name| match load_sky_faces(name, loader, assets) {
+            Ok(faces) => Some(faces),
+            Err(e) => {
+                error!(error = ?e, sky = name, "failed to load skybox");
+                assets.missing(&format!("skybox/{name}"), &e);
+                None
+            }
+        });
+    let camera = entities().find_map(|ent| match ent {
+        Entity::SkyCamera(camera) if camera.scale > 0 => Some(SkyCamera {
+            origin: map_coords(camera.origin),
+            scale: camera.scale as f32,
+            visible_clusters: Vec::new(),
+        }),
+        _ => None,
+    });
+
+    Sky { faces, camera }
+}
+
+fn load_sky_faces(
+    name: &str,
+    loader: &Loader,
+    assets: &AssetLog,
+) -> Result<[CpuTexture; 6], Error> {
+    let mut images = Vec::with_capacity(6);
+    for suffix in FACE_SUFFIXES {
+        let material = load_material(&format!("skybox/{name}{suffix}"), loader, assets)?;
+        let texture = material
+            .texture
+            .ok_or_else(|| Error::Other(format!("{} has no base texture", material.path)))?;
+        images.push(texture);
+    }
+
+    // the bottom face is often stored at a lower resolution, cubemap sides need to match
+    let width = images
+        .iter()
+        .map(|tex| tex.image.width())
+        .max()
+        .unwrap_or(1);
+    let height = images
+        .iter()
+        .map(|tex| tex.image.height())
+        .max()
+        .unwrap_or(1);
+    let faces: Vec<CpuTexture> = images
+        .into_iter()
+        .map(|texture| {
+            let image = if texture.image.width() != width || texture.image.height() != height {
+                texture
+                    .image
+                    .resize_exact(width, height, FilterType::Triangle)
+            } else {
+                texture.image
+            };
+            convert_texture(
+                TextureData {
+                    name: texture.name,
+                    image,
+                },
+                false,
+            )
+        })
+        .collect();
+
+    Ok(faces.try_into().unwrap())
+}
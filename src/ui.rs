@@ -1,3 +1,5 @@
+use crate::prop::PropAnimator;
+use crate::renderer::CullStats;
 use crate::Control;
 use three_d::egui::*;
 use three_d::{Camera, Context, FrameInput, GUI};
@@ -11,6 +13,7 @@ pub enum DebugType {
     Depth,
     Orm,
     Uv,
+    Lod,
     None,
 }
 
@@ -18,11 +21,23 @@ pub struct DebugUI {
     ui: GUI,
     pub show_bsp: bool,
     pub show_props: bool,
+    pub pvs_culling: bool,
+    pub frustum_culling: bool,
+    pub prop_fade: bool,
+    pub prop_lod: bool,
+    pub show_sky: bool,
+    pub fog: bool,
     pub shadows_enabled: bool,
+    pub baked_lighting: bool,
+    pub dynamic_lights: bool,
+    pub max_lights: usize,
     pub directional_intensity: f32,
     pub ambient_intensity: f32,
     pub depth_max: f32,
     pub fov: f32,
+    /// Distance of the far plane
+    pub view_distance: f32,
+    pub max_view_distance: f32,
     pub debug_type: DebugType,
 }
 
@@ -32,11 +47,22 @@ impl DebugUI {
             ui: three_d::GUI::new(context),
             show_bsp: true,
             show_props: true,
+            pvs_culling: true,
+            frustum_culling: true,
+            prop_fade: true,
+            prop_lod: true,
+            show_sky: true,
+            fog: true,
             shadows_enabled: false,
+            baked_lighting: true,
+            dynamic_lights: false,
+            max_lights: 8,
             directional_intensity: 1.0,
             ambient_intensity: 0.2,
             depth_max: 30.0,
             fov: 60.0,
+            view_distance: 45.0,
+            max_view_distance: 45.0,
             debug_type: DebugType::None,
         }
     }
@@ -45,6 +71,9 @@ impl DebugUI {
         &mut self,
         frame_input: &mut FrameInput,
         camera: &Camera,
+        cluster: Option<i16>,
+        cull_stats: CullStats,
+        mut animations: Vec<&mut PropAnimator>,
         control: &mut C,
     ) -> (bool, u32) {
         let mut panel_width = 0;
@@ -61,8 +90,26 @@ impl DebugUI {
                     ui.label("Visibility options");
                     ui.checkbox(&mut self.show_bsp, "Map");
                     ui.checkbox(&mut self.show_props, "Props");
+                    ui.checkbox(&mut self.show_sky, "Skybox");
+                    ui.checkbox(&mut self.fog, "Fog");
+                    ui.checkbox(&mut self.pvs_culling, "Visibility culling");
+                    ui.checkbox(&mut self.frustum_culling, "Frustum culling");
+                    ui.checkbox(&mut self.prop_fade, "Prop fading");
+                    ui.checkbox(&mut self.prop_lod, "Prop LOD");
+                    ui.add(Label::new(format!(
+                        "\tdrawn: {}, culled: {}",
+                        cull_stats.drawn, cull_stats.culled
+                    )));
+
+                    if !animations.is_empty() {
+                        ui.label("Animations");
+                        for animator in animations.iter_mut() {
+                            ui.checkbox(&mut animator.playing, animator.animation.name.as_str());
+                        }
+                    }
 
                     ui.label("Light options");
+                    ui.checkbox(&mut self.baked_lighting, "Baked lighting");
                     ui.add(
                         Slider::new(&mut self.ambient_intensity, 0.0..=1.0)
                             .text("Ambient intensity"),
@@ -72,6 +119,8 @@ impl DebugUI {
                             .text("Directional intensity"),
                     );
                     ui.checkbox(&mut self.shadows_enabled, "Shadows");
+                    ui.checkbox(&mut self.dynamic_lights, "Dynamic lights");
+                    ui.add(Slider::new(&mut self.max_lights, 1..=32).text("Max lights"));
 
                     ui.label("Debug options");
                     ui.radio_value(&mut self.debug_type, DebugType::None, "None");
@@ -81,15 +130,26 @@ impl DebugUI {
                     ui.radio_value(&mut self.debug_type, DebugType::Depth, "Depth");
                     ui.radio_value(&mut self.debug_type, DebugType::Uv, "UV");
                     ui.radio_value(&mut self.debug_type, DebugType::Orm, "ORM");
+                    ui.radio_value(&mut self.debug_type, DebugType::Lod, "Prop LOD");
 
                     ui.label("View options");
-                    ui.add(Slider::new(&mut self.depth_max, 1.0..=30.0).text("Depth max"));
+                    ui.add(
+                        Slider::new(&mut self.depth_max, 1.0..=self.view_distance)
+                            .text("Depth max"),
+                    );
                     ui.add(Slider::new(&mut self.fov, 45.0..=90.0).text("FOV"));
+                    ui.add(
+                        Slider::new(&mut self.view_distance, 1.0..=self.max_view_distance)
+                            .text("View distance"),
+                    );
 
                     ui.label("Position");
                     ui.add(Label::new(format!("\tx: {}", camera.position().x)));
                     ui.add(Label::new(format!("\ty: {}", camera.position().y)));
                     ui.add(Label::new(format!("\tz: {}", camera.position().z)));
+                    if let Some(cluster) = cluster {
+                        ui.add(Label::new(format!("\tcluster: {}", cluster)));
+                    }
 
                     control.ui(ui);
                 });
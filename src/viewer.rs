@@ -0,0 +1,215 @@
+use crate::loader::Loader;
+use crate::material::{AssetLog, MaterialSet};
+use crate::prop::{
+    body_models, body_value, load_prop, prop_texture_to_material, prop_to_meshes, LoadedProp,
+    PropData,
+};
+use crate::Error;
+use std::collections::HashMap;
+use std::path::Path;
+use three_d::egui::{ComboBox, SidePanel, Slider};
+use three_d::*;
+
+/// A single model on an orbit camera, with pickers for the skin, bodygroups and level of detail
+struct ModelViewer {
+    name: String,
+    prop: LoadedProp,
+    loader: Loader,
+    assets: AssetLog,
+    skin: usize,
+    /// The selected model for every body part
+    body: Vec<usize>,
+    lod: usize,
+    /// Loaded materials by path, kept so switching skins doesn't reload the textures
+    materials: HashMap<String, PhysicalMaterial>,
+    meshes: Vec<Gm<Mesh, PhysicalMaterial>>,
+}
+
+impl ModelViewer {
+    fn part_sizes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.prop
+            .mdl
+            .body_parts
+            .iter()
+            .map(|part| part.models.len())
+    }
+
+    /// Rebuild the meshes for the current skin, body and level of detail
+    fn build_meshes(&mut self, context: &Context) {
+        let data = PropData {
+            name: &self.name,
+            prop: &self.prop,
+            skin: self.skin as i32,
+            body: body_value(self.part_sizes(), &self.body),
+        };
+        let used_materials = MaterialSet::new(&self.loader);
+        let primitives: Vec<_> = prop_to_meshes(&data, self.lod, &used_materials, true).collect();
+        let material_names = used_materials.into_materials();
+        for name in &material_names {
+            if !self.materials.contains_key(name) {
+                let material = prop_texture_to_material(name, &self.loader, &self.assets);
+                let material = PhysicalMaterial::new(context, &material);
+                self.materials.insert(name.clone(), material);
+            }
+        }
+
+        self.meshes = primitives
+            .iter()
+            .filter_map(|primitive| match &primitive.geometry {
+                three_d_asset::Geometry::Triangles(mesh) => Some((primitive, mesh)),
+                _ => None,
+            })
+            .map(|(primitive, mesh)| {
+                let material = primitive
+                    .material_index
+                    .and_then(|index| material_names.get(index))
+                    .and_then(|name| self.materials.get(name))
+                    .cloned()
+                    .unwrap_or_default();
+                Gm::new(Mesh::new(context, mesh), material)
+            })
+            .collect();
+    }
+
+    fn bounds(&self) -> AxisAlignedBoundingBox {
+        let mut bounds = AxisAlignedBoundingBox::EMPTY;
+        for mesh in &self.meshes {
+            bounds.expand_with_aabb(&mesh.aabb());
+        }
+        bounds
+    }
+
+    /// Draw the side panel, returns true if the meshes need to be rebuilt
+    fn ui(&mut self, ui: &mut egui::Ui) -> bool {
+        let (skin, body, lod) = (self.skin, self.body.clone(), self.lod);
+
+        ui.heading(&self.name);
+        ui.label("Skin");
+        let skins = self.prop.skin_count();
+        ui.add(Slider::new(&mut self.skin, 0..=skins.saturating_sub(1)).text("skin"));
+
+        ui.label("Bodygroups");
+        for (i, (part, selected)) in self
+            .prop
+            .mdl
+            .body_parts
+            .iter()
+            .zip(self.body.iter_mut())
+            .enumerate()
+        {
+            let label = |index: usize| {
+                let name = part.models.get(index).map(|model| model.name.as_str());
+                format!("{index}: {}", name.unwrap_or_default())
+            };
+            ComboBox::from_label(format!("part {i}"))
+                .selected_text(label(*selected))
+                .show_ui(ui, |ui| {
+                    for index in 0..part.models.len() {
+                        ui.selectable_value(selected, index, label(index));
+                    }
+                });
+        }
+
+        ui.label("Level of detail");
+        let lods = self.prop.lod_switch_points().len();
+        ui.add(Slider::new(&mut self.lod, 0..=lods.saturating_sub(1)).text("lod"));
+
+        ui.label("  rotate with left drag, zoom with scroll");
+
+        skin != self.skin || body != self.body || lod != self.lod
+    }
+}
+
+/// The name to load a model by through the loader, files on disk are loaded from their directory
+fn model_name(loader: &mut Loader, path: &str) -> Result<String, Error> {
+    let file = Path::new(path);
+    if file.is_file() {
+        let dir = file
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        loader.add_source(dir.to_path_buf());
+        let name = file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Error::Other(format!("invalid model path {path}")))?;
+        return Ok(name.into());
+    }
+    let name = path.replace('\\', "/").to_ascii_lowercase();
+    Ok(if name.starts_with("models/") {
+        name
+    } else {
+        format!("models/{name}")
+    })
+}
+
+/// Open a model from a path on disk or a model name, and show it on an orbit camera
+pub fn view_model(window: Window, mut loader: Loader, path: &str) -> Result<(), Error> {
+    let name = model_name(&mut loader, path)?;
+    let assets = AssetLog::default();
+    let prop = load_prop(&loader, &name, &assets)?;
+    let context = window.gl();
+
+    let mut viewer = ModelViewer {
+        body: body_models(prop.mdl.body_parts.iter().map(|part| part.models.len()), 0),
+        name,
+        prop,
+        loader,
+        assets,
+        skin: 0,
+        lod: 0,
+        materials: HashMap::new(),
+        meshes: Vec::new(),
+    };
+    viewer.build_meshes(&context);
+
+    let bounds = viewer.bounds();
+    let (center, size) = if bounds.is_empty() {
+        (Vec3::zero(), 1.0)
+    } else {
+        (bounds.center(), bounds.size().magnitude().max(0.01))
+    };
+    let mut camera = Camera::new_perspective(
+        window.viewport(),
+        center + vec3(0.0, 0.3, 1.0).normalize() * size * 1.5,
+        center,
+        vec3(0.0, 1.0, 0.0),
+        degrees(60.0),
+        size * 0.01,
+        size * 20.0,
+    );
+    let mut control = OrbitControl::new(center, size * 0.1, size * 10.0);
+    let ambient = AmbientLight::new(&context, 0.4, Srgba::WHITE);
+    let directional = DirectionalLight::new(&context, 2.0, Srgba::WHITE, &vec3(-1.0, -1.0, -1.0));
+    let mut gui = GUI::new(&context);
+
+    window.render_loop(move |mut frame_input| {
+        let mut changed = false;
+        gui.update(
+            &mut frame_input.events,
+            frame_input.accumulated_time,
+            frame_input.viewport,
+            frame_input.device_pixel_ratio,
+            |gui_context| {
+                SidePanel::left("side_panel").show(gui_context, |ui| {
+                    changed = viewer.ui(ui);
+                });
+            },
+        );
+        if changed {
+            viewer.build_meshes(&context);
+        }
+
+        camera.set_viewport(frame_input.viewport);
+        control.handle_events(&mut camera, &mut frame_input.events);
+
+        frame_input
+            .screen()
+            .clear(ClearState::color_and_depth(0.2, 0.2, 0.2, 1.0, 1.0))
+            .render(&camera, &viewer.meshes, &[&ambient, &directional])
+            .write(|| gui.render());
+        FrameOutput::default()
+    });
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use crate::bsp::{lump_data, unmap_coords};
+use std::collections::HashMap;
+use three_d::Vec3;
+use vbsp::{Bsp, Vector, VisData};
+
+const LEAVES_LUMP: usize = 10;
+
+/// Potentially visible set culling using the bsp vis data
+pub struct Visibility {
+    nodes: Vec<VisNode>,
+    /// Cluster of each leaf, in the order the nodes reference them
+    leaf_clusters: Vec<i16>,
+    vis_data: VisData,
+    /// Clusters each primitive is in, for each model, an empty list means always visible
+    pub primitive_clusters: Vec<Vec<Vec<i16>>>,
+    cluster: Option<i16>,
+    visible_clusters: Vec<bool>,
+}
+
+struct VisNode {
+    normal: Vector,
+    dist: f32,
+    children: [i32; 2],
+}
+
+impl Visibility {
+    pub fn new(data: &[u8], bsp: &Bsp) -> Option<Self> {
+        if bsp.vis_data.cluster_count == 0 {
+            return None;
+        }
+
+        // vbsp sorts the leaves by cluster, so we read them in file order ourselves
+        let (version, leaves) = lump_data(data, LEAVES_LUMP)?;
+        let leaf_size = if version == 0 { 56 } else { 32 };
+        let leaf_clusters = leaves
+            .chunks_exact(leaf_size)
+            .map(|leaf| i16::from_le_bytes([leaf[4], leaf[5]]))
+            .collect();
+
+        let nodes = bsp
+            .nodes
+            .iter()
+            .map(|node| {
+                let plane = &bsp.planes[node.plane_index as usize];
+                VisNode {
+                    normal: plane.normal,
+                    dist: plane.dist,
+                    children: node.children,
+                }
+            })
+            .collect();
+
+        Some(Visibility {
+            nodes,
+            leaf_clusters,
+            vis_data: bsp.vis_data.clone(),
+            primitive_clusters: Vec::new(),
+            cluster: None,
+            visible_clusters: Vec::new(),
+        })
+    }
+
+    /// Find the cluster containing a point, or -1 if the point is outside the world
+    pub fn cluster_at(&self, point: Vector) -> i16 {
+        let mut index = 0;
+        while index >= 0 {
+            let Some(node) = self.nodes.get(index as usize) else {
+                return -1;
+            };
+            let dot = point.x * node.normal.x + point.y * node.normal.y + point.z * node.normal.z;
+            index = if dot < node.dist {
+                node.children[1]
+            } else {
+                node.children[0]
+            };
+        }
+        self.leaf_clusters
+            .get((!index) as usize)
+            .copied()
+            .unwrap_or(-1)
+    }
+
+    /// Update the visible clusters for the camera position
+    pub fn update(&mut self, position: Vec3) {
+        let cluster = self.cluster_at(unmap_coords(position));
+        if self.cluster == Some(cluster) {
+            return;
+        }
+        self.cluster = Some(cluster);
+        self.visible_clusters = self.visible_clusters(cluster);
+    }
+
+    /// Get the set of clusters visible from a cluster, empty if the cluster is outside the world
+    pub fn visible_clusters(&self, cluster: i16) -> Vec<bool> {
+        if cluster < 0 {
+            Vec::new()
+        } else {
+            let visible = self.vis_data.visible_clusters(cluster);
+            (0..self.vis_data.cluster_count)
+                .map(|i| i as i16 == cluster || visible[i as u64])
+                .collect()
+        }
+    }
+
+    /// Clusters visible from the camera position
+    pub fn camera_clusters(&self) -> &[bool] {
+        &self.visible_clusters
+    }
+
+    pub fn is_visible_from(
+        &self,
+        visible_clusters: &[bool],
+        model: usize,
+        primitive: usize,
+    ) -> bool {
+        let Some(clusters) = self
+            .primitive_clusters
+            .get(model)
+            .and_then(|primitives| primitives.get(primitive))
+        else {
+            return true;
+        };
+        clusters.is_empty()
+            || clusters
+                .iter()
+                .any(|cluster| cluster_visible(visible_clusters, *cluster))
+    }
+
+    pub fn cluster(&self) -> Option<i16> {
+        self.cluster
+    }
+}
+
+/// Check if a cluster is in a visible set, unknown clusters are always visible
+pub fn cluster_visible(visible_clusters: &[bool], cluster: i16) -> bool {
+    // show everything when the camera is outside the world
+    visible_clusters.is_empty()
+        || cluster < 0
+        || visible_clusters
+            .get(cluster as usize)
+            .copied()
+            .unwrap_or(true)
+}
+
+/// Get the clusters each face is in, by face index
+pub fn face_clusters(bsp: &Bsp) -> HashMap<usize, Vec<i16>> {
+    let mut clusters: HashMap<usize, Vec<i16>> = HashMap::new();
+    for leaf in bsp.leaves.iter().filter(|leaf| leaf.cluster >= 0) {
+        let start = leaf.first_leaf_face as usize;
+        let end = start + leaf.leaf_face_count as usize;
+        for leaf_face in bsp.leaf_faces.get(start..end).unwrap_or_default() {
+            let face_clusters = clusters.entry(leaf_face.face as usize).or_default();
+            if !face_clusters.contains(&leaf.cluster) {
+                face_clusters.push(leaf.cluster);
+            }
+        }
+    }
+    clusters
+}